@@ -12,6 +12,7 @@
 // =============================
 
 mod config {
+    use super::parser::PngLimits;
     use kovi::toml;
     use kovi::utils::{load_toml_data, save_toml_data};
     use serde::{Deserialize, Serialize};
@@ -31,24 +32,108 @@ enabled = true
 # 触发指令
 commands = ["读卡", "解析卡", "看卡", "card"]
 
+# 打包指令：把角色卡数据 (PNG / JSON / CharX) 写入 PNG 头像
+pack_commands = ["打包卡", "写卡"]
+
+# 正则试运行指令：用角色卡的正则脚本处理一段示例文本，如：测正则 AI 你好
+# (文本前可写作用位置：用户 / AI / 命令 / 世界书 / 推理，默认 AI 输出)
+regex_test_commands = ["测正则", "正则测试"]
+
+# 世界书模拟指令：用一段聊天记录 (每行一条消息) 测试会激活哪些世界书条目
+lore_test_commands = ["测世界书", "世界书测试"]
+
+# 表格导入指令：把编辑后的世界书表格 (CSV / XLSX) 合并回角色卡或世界书
+# (同时附带或引用角色卡与表格，按 id 合并)
+sheet_import_commands = ["导入表格", "合并表格"]
+
 # 指令前缀 (留空则直接匹配指令)
 prefixes = []
 
 # 是否在解析完成后，发送简短的文本预览（除了发送文件外）
 text_preview = true
+
+# 是否总是把内嵌世界书额外导出为酒馆世界书文件
+# (关闭时也可以在指令后加“世界书”单独触发，如：读卡 世界书)
+export_world = false
+
+# 是否总是把正则脚本额外导出为酒馆正则脚本文件 (ZIP 打包)
+# (关闭时也可以在指令后加“正则”单独触发，如：读卡 正则)
+export_regex = false
+
+# 是否在 TXT 末尾附加“世界书诊断”：无法触发、关键词遮蔽、重复 id、已禁用的条目等
+# (关闭时也可以在指令后加“诊断”单独触发，如：读卡 诊断)
+lore_diagnostics = false
+
+# 是否总是把世界书条目额外导出为表格 (XLSX 与 CSV)，方便在表格软件中批量编辑
+# (关闭时也可以在指令后加“表格”单独触发，如：读卡 表格)
+export_sheet = false
+
+# PNG 读取限制 (防止恶意或损坏的图片占用大量内存)
+# 单个数据块与解压后文本的最大体积 (MB)
+png_max_chunk_mb = 32
+# 所有数据块的总体积上限 (MB)，也限制 CharX 中 card.json 解压后的大小
+png_max_total_mb = 128
+# 是否校验每个数据块的 CRC (开启后 CRC 错误的图片会被拒绝)
+png_verify_crc = false
 "#;
 
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct Config {
         pub enabled: bool,
         pub commands: Vec<String>,
+        #[serde(default = "default_pack_commands")]
+        pub pack_commands: Vec<String>,
+        #[serde(default = "default_regex_test_commands")]
+        pub regex_test_commands: Vec<String>,
+        #[serde(default = "default_lore_test_commands")]
+        pub lore_test_commands: Vec<String>,
+        #[serde(default = "default_sheet_import_commands")]
+        pub sheet_import_commands: Vec<String>,
         pub prefixes: Vec<String>,
         pub text_preview: bool,
+        #[serde(default)]
+        pub export_world: bool,
+        #[serde(default)]
+        pub export_regex: bool,
+        #[serde(default)]
+        pub lore_diagnostics: bool,
+        #[serde(default)]
+        pub export_sheet: bool,
+        #[serde(default = "default_png_max_chunk_mb")]
+        pub png_max_chunk_mb: usize,
+        #[serde(default = "default_png_max_total_mb")]
+        pub png_max_total_mb: usize,
+        #[serde(default)]
+        pub png_verify_crc: bool,
 
         #[serde(skip)]
         config_path: PathBuf,
     }
 
+    fn default_pack_commands() -> Vec<String> {
+        vec!["打包卡".to_string(), "写卡".to_string()]
+    }
+
+    fn default_regex_test_commands() -> Vec<String> {
+        vec!["测正则".to_string(), "正则测试".to_string()]
+    }
+
+    fn default_lore_test_commands() -> Vec<String> {
+        vec!["测世界书".to_string(), "世界书测试".to_string()]
+    }
+
+    fn default_sheet_import_commands() -> Vec<String> {
+        vec!["导入表格".to_string(), "合并表格".to_string()]
+    }
+
+    fn default_png_max_chunk_mb() -> usize {
+        32
+    }
+
+    fn default_png_max_total_mb() -> usize {
+        128
+    }
+
     impl Config {
         pub fn load(data_dir: PathBuf) -> Arc<RwLock<Self>> {
             if !data_dir.exists() {
@@ -70,423 +155,5914 @@ text_preview = true
         pub fn save(&self) {
             let _ = save_toml_data(self, &self.config_path);
         }
+
+        pub fn png_limits(&self) -> PngLimits {
+            PngLimits {
+                max_chunk_size: self.png_max_chunk_mb.saturating_mul(1024 * 1024),
+                max_total_size: self.png_max_total_mb.saturating_mul(1024 * 1024),
+                verify_crc: self.png_verify_crc,
+            }
+        }
     }
 }
 
 mod types {
     use serde::{Deserialize, Serialize};
+    use serde_json::{Map, Value};
+    use std::collections::BTreeMap;
 
     /// 根结构体：角色卡 V3 规范
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct CharaCardV3 {
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub spec: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub spec_version: String,
         pub data: CharacterData,
+
+        /// 未建模的顶层字段，导出时原样写回，保证 JSON 可无损导入
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
     }
 
     /// 核心角色数据
     #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct CharacterData {
         /// 基础信息
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub name: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub description: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub personality: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub scenario: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub first_mes: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub mes_example: String,
 
         /// 创建者信息
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub creator_notes: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub system_prompt: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub post_history_instructions: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub creator: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub character_version: String,
 
         /// 列表数据
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::texts")]
         pub alternate_greetings: Vec<String>,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::keys")]
         pub tags: Vec<String>,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::texts")]
         pub group_only_greetings: Vec<String>,
 
-        /// 嵌套结构 (使用 Option 处理 V2 格式或缺失情况)
-        #[serde(default)]
+        /// 嵌套结构 (使用 Option 处理 V2 格式或缺失情况，缺失时导出也不写出)
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_object",
+            skip_serializing_if = "Option::is_none"
+        )]
         pub character_book: Option<CharacterBook>,
-        #[serde(default)]
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_object",
+            skip_serializing_if = "Option::is_none"
+        )]
         pub extensions: Option<CardExtensions>,
+
+        /// V3 新增字段 (V2 卡中不存在时不输出)
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_list",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub assets: Option<Vec<CardAsset>>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_text",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub nickname: Option<String>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_text_map",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub creator_notes_multilingual: Option<BTreeMap<String, String>>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_texts",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub source: Option<Vec<String>>,
+        /// Unix 时间戳 (秒)
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_i64",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub creation_date: Option<i64>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_i64",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub modification_date: Option<i64>,
+
+        /// 未建模的字段 (如未来新增的规范字段)，导出时原样写回
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+
+    /// V3 资源声明 (头像、背景、表情等)
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
+    pub struct CardAsset {
+        #[serde(rename = "type", default, deserialize_with = "lenient::text")]
+        pub asset_type: String,
+        /// embeded:// (CharX 内嵌)、ccdefault: (默认头像)、https:// 或 data: URL
+        #[serde(default, deserialize_with = "lenient::text")]
+        pub uri: String,
+        #[serde(default, deserialize_with = "lenient::text")]
+        pub name: String,
+        #[serde(default, deserialize_with = "lenient::text")]
+        pub ext: String,
+
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
     }
 
     /// 世界书/传说书结构
     #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct CharacterBook {
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::entries")]
         pub entries: Vec<LoreEntry>,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub name: String,
-        #[serde(default)]
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_text",
+            skip_serializing_if = "Option::is_none"
+        )]
         pub description: Option<String>,
-        #[serde(default)]
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
         pub scan_depth: Option<i32>,
+
+        /// 未建模的字段 (如 extensions、recursive_scanning)
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
     }
 
     /// 世界书条目 (Lore Entry)
-    #[derive(Debug, Serialize, Deserialize, Clone)]
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct LoreEntry {
-        #[serde(default)]
-        pub id: i32,
-        #[serde(default)]
+        /// CCv3 中可以省略，省略的条目不参与重复 id 检查
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub id: Option<i32>,
+        #[serde(default, deserialize_with = "lenient::keys")]
         pub keys: Vec<String>,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::keys")]
         pub secondary_keys: Vec<String>,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub comment: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub content: String,
 
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::bool")]
         pub constant: bool,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::bool")]
         pub selective: bool,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::int")]
         pub insertion_order: i32,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::bool")]
         pub enabled: bool,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::position")]
         pub position: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::bool")]
         pub use_regex: bool,
 
-        #[serde(default)]
-        pub extensions: serde_json::Value, // 简化处理，只存不读细节，除非需要打印
+        #[serde(default, deserialize_with = "lenient::object")]
+        pub extensions: LoreExtensions,
+
+        /// 未建模的条目字段
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+
+    /// SillyTavern 写入条目 extensions 的激活设置
+    /// 缺失的字段表示使用酒馆全局设置
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
+    pub struct LoreExtensions {
+        /// 0 角色定义前, 1 角色定义后, 2 作者注释前, 3 作者注释后, 4 指定深度, 5 示例前, 6 示例后
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub position: Option<i32>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub depth: Option<i32>,
+        /// 0 system, 1 user, 2 assistant (仅 position = 4 时有效)
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub role: Option<i32>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub display_index: Option<i32>,
+
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub probability: Option<i32>,
+        #[serde(
+            rename = "useProbability",
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub use_probability: Option<bool>,
+        /// 0 AND ANY, 1 NOT ALL, 2 NOT ANY, 3 AND ALL
+        #[serde(
+            rename = "selectiveLogic",
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub selective_logic: Option<i32>,
+
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_text",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub group: Option<String>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub group_override: Option<bool>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub group_weight: Option<i32>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub use_group_scoring: Option<bool>,
+
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub sticky: Option<i32>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub cooldown: Option<i32>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub delay: Option<i32>,
+
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub exclude_recursion: Option<bool>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub prevent_recursion: Option<bool>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_int",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub scan_depth: Option<i32>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub match_whole_words: Option<bool>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub case_sensitive: Option<bool>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_text",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub automation_id: Option<String>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_bool",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub vectorized: Option<bool>,
+
+        /// delay_until_recursion 等未建模字段
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+
+    impl LoreEntry {
+        /// 拆分内容开头的 CCv3 装饰器 (`@@depth 4` 等) 与正文
+        pub fn decorated_content(&self) -> super::decorators::DecoratedContent<'_> {
+            super::decorators::parse(&self.content)
+        }
+
+        /// 报告中用来指代条目的名称：注释，其次是第一个关键词，最后是 id
+        pub fn display_name(&self) -> String {
+            if !self.comment.trim().is_empty() {
+                self.comment.clone()
+            } else if let Some(key) = self.keys.iter().find(|k| !k.trim().is_empty()) {
+                key.clone()
+            } else {
+                self.id
+                    .map_or_else(|| "未命名条目".to_string(), |id| format!("#{}", id))
+            }
+        }
     }
 
     /// 角色卡扩展功能
     #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct CardExtensions {
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::bool")]
         pub fav: bool,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub world: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub talkativeness: String,
-        #[serde(default)]
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_object",
+            skip_serializing_if = "Option::is_none"
+        )]
         pub depth_prompt: Option<DepthPrompt>,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::list")]
         pub regex_scripts: Vec<RegexScript>,
+
+        /// 第三方扩展 (chub、risuai、酒馆助手等)
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
     }
 
     /// 深度提示词配置
     #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct DepthPrompt {
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::int")]
         pub depth: i32,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub prompt: String,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub role: String,
+
+        /// 未建模的字段
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
     }
 
     /// 正则脚本配置
     #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct RegexScript {
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::text")]
         pub id: String,
-        #[serde(rename = "scriptName", default)]
+        #[serde(rename = "scriptName", default, deserialize_with = "lenient::text")]
         pub script_name: String,
-        #[serde(rename = "findRegex", default)]
+        #[serde(rename = "findRegex", default, deserialize_with = "lenient::text")]
         pub find_regex: String,
-        #[serde(rename = "replaceString", default)]
+        #[serde(rename = "replaceString", default, deserialize_with = "lenient::text")]
         pub replace_string: String,
-        #[serde(rename = "runOnEdit", default)]
+        #[serde(rename = "runOnEdit", default, deserialize_with = "lenient::bool")]
         pub run_on_edit: bool,
-        #[serde(default)]
+        #[serde(default, deserialize_with = "lenient::bool")]
         pub disabled: bool,
-        #[serde(rename = "markdownOnly", default)]
+        #[serde(rename = "markdownOnly", default, deserialize_with = "lenient::bool")]
         pub markdown_only: bool,
-        #[serde(rename = "promptOnly", default)]
+        #[serde(rename = "promptOnly", default, deserialize_with = "lenient::bool")]
         pub prompt_only: bool,
-        #[serde(rename = "minDepth")]
+        #[serde(rename = "minDepth", default, deserialize_with = "lenient::opt_int")]
         pub min_depth: Option<i32>,
-        #[serde(rename = "maxDepth")]
+        #[serde(rename = "maxDepth", default, deserialize_with = "lenient::opt_int")]
         pub max_depth: Option<i32>,
-    }
-}
 
-mod parser {
-    use super::types::{CharaCardV3, CharacterData};
-    use anyhow::{Result, anyhow};
-    use base64::{Engine as _, engine::general_purpose};
-    use kovi::serde_json;
-    use std::io::{Cursor, Read, Seek, SeekFrom};
+        /// 作用位置：1 用户输入, 2 AI 输出, 3 斜杠命令, 5 世界书, 6 推理 (0 为已废弃的仅显示)
+        #[serde(default, deserialize_with = "lenient::ints")]
+        pub placement: Vec<i32>,
+        /// 查找正则中的宏 ({{char}} 等)：0 不替换, 1 原样替换, 2 转义后替换
+        /// 旧版酒馆写入的是布尔值
+        #[serde(
+            rename = "substituteRegex",
+            default,
+            deserialize_with = "deserialize_substitute_regex"
+        )]
+        pub substitute_regex: i32,
+        /// 替换前从匹配文本中删除的字符串
+        #[serde(rename = "trimStrings", default, deserialize_with = "lenient::texts")]
+        pub trim_strings: Vec<String>,
 
-    /// 从 PNG 字节中解析角色卡数据
-    /// 返回: (核心数据结构, 完整的 JSON 字符串)
-    pub fn parse_png(bytes: &[u8]) -> Result<(CharacterData, String)> {
-        let mut cursor = Cursor::new(bytes);
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
 
-        // 1. 验证 PNG 头
-        let mut header = [0u8; 8];
-        cursor.read_exact(&mut header)?;
-        if header != [137, 80, 78, 71, 13, 10, 26, 10] {
-            return Err(anyhow!("不是有效的 PNG 图片"));
+    fn deserialize_substitute_regex<'de, D>(deserializer: D) -> Result<i32, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        match Value::deserialize(deserializer)? {
+            // 旧版酒馆的写法，不算类型错误
+            Value::Bool(b) => Ok(i32::from(b)),
+            value => lenient::int(value).map_err(serde::de::Error::custom),
         }
+    }
 
-        let mut ccv3_data: Option<String> = None;
-        let mut chara_data: Option<String> = None;
+    /// SillyTavern 独立世界书文件 (worlds/*.json)
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
+    pub struct WorldInfo {
+        /// 以 uid 字符串为键的条目表
+        #[serde(default, deserialize_with = "lenient::keyed")]
+        pub entries: BTreeMap<String, WorldEntry>,
+        #[serde(
+            default,
+            deserialize_with = "lenient::opt_text",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub name: Option<String>,
 
-        // 2. 遍历 Chunks
-        loop {
-            let mut len_buf = [0u8; 4];
-            if cursor.read_exact(&mut len_buf).is_err() {
-                break;
-            }
-            let length = u32::from_be_bytes(len_buf) as u64;
+        /// originalData 等未建模字段
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
 
-            let mut type_buf = [0u8; 4];
-            cursor.read_exact(&mut type_buf)?;
-            let chunk_type = std::str::from_utf8(&type_buf).unwrap_or("");
+    /// 世界书文件中的条目，字段名与角色卡内嵌世界书不同 (key / keysecondary / order ...)
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorldEntry {
+        #[serde(default, deserialize_with = "lenient::int")]
+        pub uid: i32,
+        #[serde(default, deserialize_with = "lenient::keys")]
+        pub key: Vec<String>,
+        #[serde(default, deserialize_with = "lenient::keys")]
+        pub keysecondary: Vec<String>,
+        #[serde(default, deserialize_with = "lenient::text")]
+        pub comment: String,
+        #[serde(default, deserialize_with = "lenient::text")]
+        pub content: String,
+        #[serde(default, deserialize_with = "lenient::bool")]
+        pub constant: bool,
+        #[serde(default, deserialize_with = "lenient::bool")]
+        pub vectorized: bool,
+        #[serde(default, deserialize_with = "lenient::bool")]
+        pub selective: bool,
+        #[serde(default, deserialize_with = "lenient::int")]
+        pub selective_logic: i32,
+        #[serde(default, deserialize_with = "lenient::int")]
+        pub order: i32,
+        #[serde(default, deserialize_with = "lenient::int")]
+        pub position: i32,
+        #[serde(default, deserialize_with = "lenient::bool")]
+        pub disable: bool,
+        #[serde(default, deserialize_with = "lenient::bool")]
+        pub exclude_recursion: bool,
+        #[serde(default, deserialize_with = "lenient::bool")]
+        pub prevent_recursion: bool,
+        #[serde(
+            default = "default_probability",
+            deserialize_with = "lenient_probability"
+        )]
+        pub probability: i32,
+        #[serde(default = "default_true", deserialize_with = "lenient_true")]
+        pub use_probability: bool,
+        #[serde(default = "default_depth", deserialize_with = "lenient_depth")]
+        pub depth: i32,
+        #[serde(default, deserialize_with = "lenient::text")]
+        pub group: String,
+        #[serde(default, deserialize_with = "lenient::bool")]
+        pub group_override: bool,
+        #[serde(
+            default = "default_probability",
+            deserialize_with = "lenient_probability"
+        )]
+        pub group_weight: i32,
+        #[serde(default, deserialize_with = "lenient::opt_int")]
+        pub scan_depth: Option<i32>,
+        #[serde(default, deserialize_with = "lenient::opt_bool")]
+        pub case_sensitive: Option<bool>,
+        #[serde(default, deserialize_with = "lenient::opt_bool")]
+        pub match_whole_words: Option<bool>,
+        #[serde(default, deserialize_with = "lenient::opt_bool")]
+        pub use_group_scoring: Option<bool>,
+        #[serde(default, deserialize_with = "lenient::text")]
+        pub automation_id: String,
+        #[serde(default, deserialize_with = "lenient::opt_int")]
+        pub role: Option<i32>,
+        #[serde(default, deserialize_with = "lenient::opt_int")]
+        pub sticky: Option<i32>,
+        #[serde(default, deserialize_with = "lenient::opt_int")]
+        pub cooldown: Option<i32>,
+        #[serde(default, deserialize_with = "lenient::opt_int")]
+        pub delay: Option<i32>,
+        #[serde(default, deserialize_with = "lenient::opt_int")]
+        pub display_index: Option<i32>,
 
-            if chunk_type == "tEXt" {
-                let mut data_buf = vec![0u8; length as usize];
-                cursor.read_exact(&mut data_buf)?;
+        /// addMemo、delayUntilRecursion 等未建模字段
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
 
-                if let Some(null_pos) = data_buf.iter().position(|&b| b == 0)
-                    && let Ok(keyword) = std::str::from_utf8(&data_buf[..null_pos])
-                {
-                    let text_bytes = &data_buf[null_pos + 1..];
-                    if let Ok(text) = std::str::from_utf8(text_bytes) {
-                        let key_lower = keyword.to_lowercase();
-                        if key_lower == "ccv3" {
-                            ccv3_data = Some(text.to_string());
-                        } else if key_lower == "chara" {
-                            chara_data = Some(text.to_string());
-                        }
-                    }
-                }
-                cursor.seek(SeekFrom::Current(4))?; // Skip CRC
-            } else {
-                cursor.seek(SeekFrom::Current((length + 4) as i64))?;
-            }
-        }
+    fn default_probability() -> i32 {
+        100
+    }
 
-        // 3. 优先处理 V3 (ccv3)
-        if let Some(b64) = ccv3_data {
-            let json_str = decode_base64(&b64)?;
-            let wrapper: CharaCardV3 =
-                serde_json::from_str(&json_str).map_err(|e| anyhow!("V3 JSON 解析失败: {}", e))?;
-            let full_json = serde_json::to_string_pretty(&wrapper)?;
-            return Ok((wrapper.data, full_json));
-        }
+    fn default_depth() -> i32 {
+        4
+    }
 
-        // 4. 降级处理 V2 (chara)
-        if let Some(b64) = chara_data {
-            let json_str = decode_base64(&b64)?;
-            // V2 直接对应 CharacterData 的字段，只是没有 extensions 和 character_book
-            let card: CharacterData =
-                serde_json::from_str(&json_str).map_err(|e| anyhow!("V2 JSON 解析失败: {}", e))?;
-            let full_json = serde_json::to_string_pretty(&card)?;
-            return Ok((card, full_json));
-        }
+    fn default_true() -> bool {
+        true
+    }
+
+    // 世界书文件中写成 null 的设置与缺省一样取酒馆的默认值
 
-        Err(anyhow!("未在图片中找到角色卡信息 (chara/ccv3)"))
+    fn lenient_probability<'de, D>(deserializer: D) -> Result<i32, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(lenient::opt_int(deserializer)?.unwrap_or_else(default_probability))
     }
 
-    fn decode_base64(input: &str) -> Result<String> {
-        let bytes = general_purpose::STANDARD.decode(input)?;
-        let s = String::from_utf8(bytes)?;
-        Ok(s)
+    fn lenient_depth<'de, D>(deserializer: D) -> Result<i32, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(lenient::opt_int(deserializer)?.unwrap_or_else(default_depth))
     }
 
-    /// 生成易读的文本报告
-    pub fn format_readable_text(card: &CharacterData) -> String {
-        let mut s = String::new();
-        let sep_line = "-".repeat(40);
-        let sep_block = format!("\n{}\n", sep_line);
+    fn lenient_true<'de, D>(deserializer: D) -> Result<bool, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(lenient::opt_bool(deserializer)?.unwrap_or_else(default_true))
+    }
 
-        // --- 头部信息 ---
-        s.push_str(&format!("【角色名称】: {}\n", card.name));
-        if !card.creator.is_empty() {
-            s.push_str(&format!("【创 建 者】: {}\n", card.creator));
-        }
-        if !card.character_version.is_empty() {
-            s.push_str(&format!("【版    本】: {}\n", card.character_version));
+    pub mod lenient {
+        //! 宽松读取：第三方编辑器导出的卡片常有字段类型写错 (talkativeness 写成数字、
+        //! id 写成文本、tags 写成逗号分隔的字符串等)，serde 遇到一处就会让整张卡解析失败。
+        //!
+        //! 上面的字段通过 `deserialize_with` 使用这里的函数：能转换的按规范类型读取，
+        //! 无法转换的按缺省值处理；在 `collect` 中反序列化时，每处转换记一条说明。
+
+        use super::LoreEntry;
+        use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+        use serde_json::Value;
+        use std::cell::RefCell;
+        use std::collections::BTreeMap;
+        use std::fmt;
+        use std::marker::PhantomData;
+
+        thread_local! {
+            /// 只在 `collect` 期间为 Some，其余场合 (如导入正则预设) 不记录
+            static WARNINGS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
         }
-        if !card.tags.is_empty() {
-            s.push_str(&format!("【标    签】: {}\n", card.tags.join(", ")));
+
+        /// 执行 `f`，同时返回期间记录的全部转换说明
+        pub fn collect<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+            let outer = WARNINGS.replace(Some(Vec::new()));
+            let result = f();
+            let warnings = WARNINGS.replace(outer).unwrap_or_default();
+            (result, warnings)
         }
 
-        // --- 核心设定 ---
+        fn warn(message: String) {
+            WARNINGS.with_borrow_mut(|warnings| {
+                if let Some(warnings) = warnings {
+                    warnings.push(message);
+                }
+            });
+        }
 
-        // 描述
-        s.push_str(&sep_block);
-        s.push_str("【角色描述 (Description)】\n\n");
-        s.push_str(&card.description);
-        s.push('\n');
+        fn converted(value: &Value, expect: &str) {
+            warn(format!(
+                "{} {} 已按{}读取",
+                kind_name(value),
+                preview(value),
+                expect
+            ));
+        }
 
-        // 开场白
-        s.push_str(&sep_block);
-        s.push_str("【开场白 (First Message)】\n\n");
-        s.push_str(&card.first_mes);
-        s.push('\n');
+        fn failed(value: &Value, expect: &str) {
+            warn(format!(
+                "无法将{} {} 读取为{}，已按缺省值处理",
+                kind_name(value),
+                preview(value),
+                expect
+            ));
+        }
 
-        // 备用开场白
-        if !card.alternate_greetings.is_empty() {
-            s.push_str(&sep_block);
-            s.push_str("【备用开场白 (Alternate Greetings)】\n");
-            for (i, msg) in card.alternate_greetings.iter().enumerate() {
-                s.push_str(&format!("\n# 备用 {}\n{}\n", i + 1, msg));
-            }
+        fn dropped(value: &Value, expect: &str) {
+            warn(format!(
+                "{} {} 不是{}，已忽略",
+                kind_name(value),
+                preview(value),
+                expect
+            ));
         }
 
-        // 性格
-        if !card.personality.is_empty() {
-            s.push_str(&sep_block);
-            s.push_str("【性格 (Personality)】\n\n");
-            s.push_str(&card.personality);
-            s.push('\n');
+        pub fn text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+            Ok(opt_text(deserializer)?.unwrap_or_default())
         }
 
-        // 场景
-        if !card.scenario.is_empty() {
-            s.push_str(&sep_block);
-            s.push_str("【场景 (Scenario)】\n\n");
-            s.push_str(&card.scenario);
-            s.push('\n');
+        pub fn opt_text<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<String>, D::Error> {
+            Ok(to_text(Value::deserialize(deserializer)?))
         }
 
-        // 对话示例
-        if !card.mes_example.is_empty() {
-            s.push_str(&sep_block);
-            s.push_str("【对话示例 (Example Messages)】\n\n");
-            s.push_str(&card.mes_example);
-            s.push('\n');
+        fn to_text(value: Value) -> Option<String> {
+            match value {
+                Value::Null => None,
+                Value::String(s) => Some(s),
+                Value::Number(_) | Value::Bool(_) => {
+                    converted(&value, "文本");
+                    Some(value.to_string())
+                }
+                // 按行拆开存放的长文本
+                Value::Array(ref items) if items.iter().all(Value::is_string) => {
+                    converted(&value, "文本");
+                    let lines: Vec<&str> = items.iter().filter_map(Value::as_str).collect();
+                    Some(lines.join("\n"))
+                }
+                _ => {
+                    failed(&value, "文本");
+                    None
+                }
+            }
         }
 
-        // --- 高级设定 ---
+        pub fn int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+            Ok(opt_int(deserializer)?.unwrap_or_default())
+        }
 
-        // 系统提示词
-        if !card.system_prompt.is_empty() {
-            s.push_str(&sep_block);
-            s.push_str("【系统提示词 (System Prompt)】\n\n");
-            s.push_str(&card.system_prompt);
-            s.push('\n');
+        pub fn opt_int<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<i32>, D::Error> {
+            let value = Value::deserialize(deserializer)?;
+            Ok(integer(value, i32::MIN.into(), i32::MAX.into()).map(|n| n as i32))
         }
 
-        if !card.post_history_instructions.is_empty() {
-            s.push_str(&sep_block);
-            s.push_str("【历史后提示词 (Post History Instructions)】\n\n");
-            s.push_str(&card.post_history_instructions);
-            s.push('\n');
+        pub fn opt_i64<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<i64>, D::Error> {
+            Ok(integer(
+                Value::deserialize(deserializer)?,
+                i64::MIN,
+                i64::MAX,
+            ))
         }
 
-        // --- 扩展内容 (正则 & 深度提示) ---
-        if let Some(ext) = &card.extensions {
-            // 深度提示词
-            if let Some(dp) = &ext.depth_prompt {
-                s.push_str(&sep_block);
-                s.push_str("【深度提示词 (Depth Prompt)】\n");
-                s.push_str(&format!("Depth: {} | Role: {}\n\n", dp.depth, dp.role));
-                s.push_str(&dp.prompt);
-                s.push('\n');
+        /// 读取整数并限制在 `min..=max`；100.0 这样的整值小数直接读取，不算转换
+        fn integer(value: Value, min: i64, max: i64) -> Option<i64> {
+            let number = match &value {
+                Value::Null => return None,
+                Value::Number(n) => match n.as_i64() {
+                    Some(i) => {
+                        if (min..=max).contains(&i) {
+                            return Some(i);
+                        }
+                        i as f64
+                    }
+                    None => n.as_f64().unwrap_or(f64::NAN),
+                },
+                Value::String(s) => s.trim().parse::<f64>().unwrap_or(f64::NAN),
+                Value::Bool(b) => f64::from(u8::from(*b)),
+                _ => f64::NAN,
+            };
+            if !number.is_finite() {
+                failed(&value, "整数");
+                return None;
             }
 
-            // 正则脚本
-            if !ext.regex_scripts.is_empty() {
-                s.push_str(&sep_block);
-                s.push_str("【正则脚本 (Regex Scripts)】\n");
-                for (i, script) in ext.regex_scripts.iter().enumerate() {
-                    let status = if script.disabled {
-                        "(禁用)"
-                    } else {
-                        "(启用)"
-                    };
-                    s.push_str(&format!(
-                        "\n## {} - {} {}\n",
-                        i + 1,
-                        script.script_name,
-                        status
-                    ));
-                    s.push_str(&format!("Regex: {}\n", script.find_regex));
-                    // 替换内容可能很长，只取前200字或者完整显示取决于需求，这里完整显示
-                    s.push_str("Replace:\n");
-                    s.push_str(&script.replace_string);
-                    s.push('\n');
-                }
+            let rounded = number.round();
+            if rounded < min as f64 || rounded > max as f64 {
+                let clamped = if rounded < 0.0 { min } else { max };
+                warn(format!(
+                    "{} {} 超出整数范围，已按 {} 读取",
+                    kind_name(&value),
+                    preview(&value),
+                    clamped
+                ));
+                return Some(clamped);
+            }
+            if !value.is_number() || rounded != number {
+                converted(&value, "整数");
             }
+            Some(rounded as i64)
         }
 
-        // --- 世界书 (Character Book) ---
-        // 这是用户特别提到缺失的部分
-        if let Some(book) = &card.character_book
-            && !book.entries.is_empty()
-        {
-            s.push_str(&sep_block);
+        pub fn bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+            Ok(opt_bool(deserializer)?.unwrap_or_default())
+        }
+
+        pub fn opt_bool<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<bool>, D::Error> {
+            let value = Value::deserialize(deserializer)?;
+            let parsed = match &value {
+                Value::Null => return Ok(None),
+                Value::Bool(b) => return Ok(Some(*b)),
+                Value::Number(n) => n.as_f64().map(|f| f != 0.0),
+                Value::String(s) => match s.trim().to_lowercase().as_str() {
+                    "true" | "1" | "yes" | "on" | "是" => Some(true),
+                    "false" | "0" | "no" | "off" | "否" | "" => Some(false),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match parsed {
+                Some(_) => converted(&value, "布尔值"),
+                None => failed(&value, "布尔值"),
+            }
+            Ok(parsed)
+        }
+
+        /// 开场白等长文本列表：单个字符串视为只有一项
+        pub fn texts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+            Ok(opt_texts(deserializer)?.unwrap_or_default())
+        }
+
+        pub fn opt_texts<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<String>>, D::Error> {
+            Ok(text_list(Value::deserialize(deserializer)?, false))
+        }
+
+        /// 关键词、标签：单个字符串按中英文逗号拆分
+        pub fn keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+            Ok(text_list(Value::deserialize(deserializer)?, true).unwrap_or_default())
+        }
+
+        fn text_list(value: Value, split: bool) -> Option<Vec<String>> {
+            match value {
+                Value::Null => None,
+                Value::Array(items) => Some(
+                    items
+                        .into_iter()
+                        .filter_map(|item| match item {
+                            Value::String(s) => Some(s),
+                            Value::Number(_) | Value::Bool(_) => to_text(item),
+                            _ => {
+                                dropped(&item, "文本");
+                                None
+                            }
+                        })
+                        .collect(),
+                ),
+                Value::String(ref s) if split => {
+                    converted(&value, "列表");
+                    Some(
+                        s.split([',', '，'])
+                            .map(str::trim)
+                            .filter(|k| !k.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                    )
+                }
+                Value::String(_) | Value::Number(_) | Value::Bool(_) => {
+                    converted(&value, "列表");
+                    let text = value.as_str().map_or(value.to_string(), str::to_string);
+                    Some(vec![text])
+                }
+                Value::Object(_) => {
+                    failed(&value, "列表");
+                    None
+                }
+            }
+        }
+
+        /// 整数列表 (正则脚本的 placement)：单个数字视为只有一项
+        pub fn ints<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<i32>, D::Error> {
+            let range = |v| integer(v, i32::MIN.into(), i32::MAX.into()).map(|n| n as i32);
+            Ok(match Value::deserialize(deserializer)? {
+                Value::Null => Vec::new(),
+                Value::Array(items) => items.into_iter().filter_map(range).collect(),
+                value @ (Value::Number(_) | Value::String(_)) => {
+                    converted(&value, "列表");
+                    range(value).into_iter().collect()
+                }
+                value => {
+                    failed(&value, "列表");
+                    Vec::new()
+                }
+            })
+        }
+
+        /// 多语言作者注释：值按文本读取，不是对象时忽略
+        pub fn opt_text_map<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<BTreeMap<String, String>>, D::Error> {
+            Ok(match Value::deserialize(deserializer)? {
+                Value::Null => None,
+                Value::Object(map) => Some(
+                    map.into_iter()
+                        .filter_map(|(k, v)| to_text(v).map(|v| (k, v)))
+                        .collect(),
+                ),
+                value => {
+                    failed(&value, "对象");
+                    None
+                }
+            })
+        }
+
+        /// 内嵌世界书条目的 position：整数按酒馆导出角色卡的规则在读取条目后改写，
+        /// 见 `entries`
+        pub fn position<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+            Ok(match Value::deserialize(deserializer)? {
+                Value::Number(n) => n.to_string(),
+                value => to_text(value).unwrap_or_default(),
+            })
+        }
+
+        /// 嵌套对象 (世界书、扩展等)：写成其他类型时忽略
+        pub fn opt_object<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserialize<'de>,
+        {
+            Ok(Item::<T>::deserialize(deserializer)?.0)
+        }
+
+        pub fn object<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserialize<'de> + Default,
+        {
+            Ok(opt_object(deserializer)?.unwrap_or_default())
+        }
+
+        /// 对象列表：不是对象的元素丢弃；写成以键索引的对象时按其中的值读取
+        pub fn list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserialize<'de>,
+        {
+            deserializer.deserialize_any(ListVisitor::<T, Vec<T>>(PhantomData))
+        }
+
+        pub fn opt_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: de::DeserializeOwned,
+        {
+            match Value::deserialize(deserializer)? {
+                Value::Null => Ok(None),
+                value => list(value).map(Some).map_err(de::Error::custom),
+            }
+        }
+
+        /// 世界书文件以 uid 为键的条目表：不是对象的条目丢弃；写成数组时以下标为键
+        pub fn keyed<'de, D, T>(deserializer: D) -> Result<BTreeMap<String, T>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserialize<'de>,
+        {
+            let items =
+                deserializer.deserialize_any(ListVisitor::<T, Vec<(String, T)>>(PhantomData))?;
+            Ok(items.into_iter().collect())
+        }
+
+        /// 内嵌世界书条目；整数 position (酒馆世界书文件的写法) 按酒馆导出角色卡的规则
+        /// 改为 before_char / after_char，原数字在没有 extensions.position 时记入其中
+        pub fn entries<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<LoreEntry>, D::Error> {
+            let mut entries: Vec<LoreEntry> = list(deserializer)?;
+            for entry in &mut entries {
+                let Ok(n) = entry.position.trim().parse::<i32>() else {
+                    continue;
+                };
+                let side = if n == 0 { "before_char" } else { "after_char" };
+                let kept = entry.extensions.position.is_none();
+                warn(format!(
+                    "数字位置 {} 已按 {} 读取{}",
+                    n,
+                    side,
+                    if kept {
+                        "，原值记入 extensions.position"
+                    } else {
+                        ""
+                    }
+                ));
+                entry.position = side.to_string();
+                entry.extensions.position.get_or_insert(n);
+            }
+            Ok(entries)
+        }
+
+        /// 列表元素：对象按 `T` 读取，其余类型记录后丢弃
+        struct Item<T>(Option<T>);
+
+        impl<'de, T: Deserialize<'de>> Deserialize<'de> for Item<T> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_any(ItemVisitor(PhantomData))
+            }
+        }
+
+        struct ItemVisitor<T>(PhantomData<T>);
+
+        impl<T> ItemVisitor<T> {
+            fn other<E>(value: Value) -> Result<Item<T>, E> {
+                dropped(&value, "对象");
+                Ok(Item(None))
+            }
+        }
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for ItemVisitor<T> {
+            type Value = Item<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("对象")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Item<T>, A::Error> {
+                T::deserialize(de::value::MapAccessDeserializer::new(map)).map(|t| Item(Some(t)))
+            }
+
+            fn visit_unit<E>(self) -> Result<Item<T>, E> {
+                Ok(Item(None))
+            }
+
+            fn visit_none<E>(self) -> Result<Item<T>, E> {
+                Ok(Item(None))
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Item<T>, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Item<T>, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Item<T>, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Item<T>, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Item<T>, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Item<T>, A::Error> {
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element::<Value>()? {
+                    items.push(item);
+                }
+                Self::other(Value::Array(items))
+            }
+        }
+
+        /// 读取对象列表，同时保留元素的键 (数组为下标)，供 `list` 与 `keyed` 共用
+        struct ListVisitor<T, C>(PhantomData<(T, C)>);
+
+        trait FromItems<T> {
+            fn push(&mut self, key: String, item: T);
+        }
+
+        impl<T> FromItems<T> for Vec<T> {
+            fn push(&mut self, _key: String, item: T) {
+                Vec::push(self, item);
+            }
+        }
+
+        impl<T> FromItems<T> for Vec<(String, T)> {
+            fn push(&mut self, key: String, item: T) {
+                Vec::push(self, (key, item));
+            }
+        }
+
+        impl<T, C: Default> ListVisitor<T, C> {
+            fn other<E>(value: Value) -> Result<C, E> {
+                failed(&value, "列表");
+                Ok(C::default())
+            }
+        }
+
+        impl<'de, T, C> Visitor<'de> for ListVisitor<T, C>
+        where
+            T: Deserialize<'de>,
+            C: FromItems<T> + Default,
+        {
+            type Value = C;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("对象列表")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<C, A::Error> {
+                let mut items = C::default();
+                let mut index = 0usize;
+                while let Some(Item(item)) = seq.next_element::<Item<T>>()? {
+                    if let Some(item) = item {
+                        items.push(index.to_string(), item);
+                    }
+                    index += 1;
+                }
+                Ok(items)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<C, A::Error> {
+                let mut items = C::default();
+                while let Some((key, Item(item))) = map.next_entry::<String, Item<T>>()? {
+                    if let Some(item) = item {
+                        items.push(key, item);
+                    }
+                }
+                Ok(items)
+            }
+
+            fn visit_unit<E>(self) -> Result<C, E> {
+                Ok(C::default())
+            }
+
+            fn visit_none<E>(self) -> Result<C, E> {
+                Ok(C::default())
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<C, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<C, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<C, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<C, E> {
+                Self::other(v.into())
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<C, E> {
+                Self::other(v.into())
+            }
+        }
+
+        fn kind_name(value: &Value) -> &'static str {
+            match value {
+                Value::Null => "null",
+                Value::Bool(_) => "布尔值",
+                Value::Number(_) => "数字",
+                Value::String(_) => "文本",
+                Value::Array(_) => "数组",
+                Value::Object(_) => "对象",
+            }
+        }
+
+        /// 说明中展示的值，过长时截断
+        fn preview(value: &Value) -> String {
+            let s = value.to_string();
+            match s.char_indices().nth(30) {
+                Some((i, _)) => format!("{}…", &s[..i]),
+                None => s,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod fixtures {
+    //! 各模块测试共用的世界书构造。
+
+    use super::types::{CharacterBook, LoreEntry};
+
+    /// 启用、插入顺序为 100 的条目
+    pub fn entry(id: i32, keys: &[&str], content: &str) -> LoreEntry {
+        LoreEntry {
+            id: Some(id),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            content: content.to_string(),
+            enabled: true,
+            insertion_order: 100,
+            ..Default::default()
+        }
+    }
+
+    pub fn book(entries: Vec<LoreEntry>) -> CharacterBook {
+        CharacterBook {
+            entries,
+            ..Default::default()
+        }
+    }
+}
+
+mod decorators {
+    //! CCv3 世界书装饰器：条目内容开头以 `@@` 起始的行，
+    //! `@@@` 行是前一个装饰器的后备 (前端不支持前者时使用)。
+
+    use std::fmt;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum DecoratorKind {
+        ActivateOnlyAfter(u32),
+        ActivateOnlyEvery(u32),
+        KeepActivateAfterMatch,
+        DontActivateAfterMatch,
+        Depth(u32),
+        InstructDepth(u32),
+        ReverseDepth(u32),
+        ReverseInstructDepth(u32),
+        Role(String),
+        ScanDepth(u32),
+        InstructScanDepth(u32),
+        IsGreeting(u32),
+        Position(String),
+        IgnoreOnMaxContext,
+        AdditionalKeys(Vec<String>),
+        ExcludeKeys(Vec<String>),
+        IsUserIcon(String),
+        Activate,
+        DontActivate,
+        DisableUiPrompt(String),
+        /// 规范外或参数无法解析的装饰器，保留原文
+        Unknown {
+            name: String,
+            args: String,
+        },
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Decorator {
+        pub kind: DecoratorKind,
+        pub fallbacks: Vec<DecoratorKind>,
+    }
+
+    /// 拆分后的条目内容
+    pub struct DecoratedContent<'a> {
+        pub decorators: Vec<Decorator>,
+        /// 去掉装饰器行之后的正文
+        pub body: &'a str,
+    }
+
+    /// 从条目内容开头解析装饰器，遇到第一行非装饰器文本即停止
+    pub fn parse(content: &str) -> DecoratedContent<'_> {
+        let mut decorators: Vec<Decorator> = Vec::new();
+        let mut rest = content;
+
+        while !rest.is_empty() {
+            let (line, next) = match rest.find('\n') {
+                Some(i) => (&rest[..i], &rest[i + 1..]),
+                None => (rest, ""),
+            };
+            let line = line.trim();
+
+            if let Some(fallback) = line.strip_prefix("@@@") {
+                let kind = parse_kind(fallback);
+                match decorators.last_mut() {
+                    Some(last) => last.fallbacks.push(kind),
+                    // 没有前置装饰器的后备行，按普通装饰器处理
+                    None => decorators.push(Decorator {
+                        kind,
+                        fallbacks: Vec::new(),
+                    }),
+                }
+            } else if let Some(decorator) = line.strip_prefix("@@") {
+                decorators.push(Decorator {
+                    kind: parse_kind(decorator),
+                    fallbacks: Vec::new(),
+                });
+            } else {
+                break;
+            }
+            rest = next;
+        }
+
+        DecoratedContent {
+            decorators,
+            body: rest,
+        }
+    }
+
+    fn parse_kind(text: &str) -> DecoratorKind {
+        let text = text.trim();
+        let (name, args) = match text.split_once(char::is_whitespace) {
+            Some((n, a)) => (n, a.trim()),
+            None => (text, ""),
+        };
+        let unknown = || DecoratorKind::Unknown {
+            name: name.to_string(),
+            args: args.to_string(),
+        };
+        let num =
+            |f: fn(u32) -> DecoratorKind| args.parse::<u32>().map(f).unwrap_or_else(|_| unknown());
+        let list = || {
+            args.split(',')
+                .map(|k| k.trim().to_string())
+                .filter(|k| !k.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        match name {
+            "activate_only_after" => num(DecoratorKind::ActivateOnlyAfter),
+            "activate_only_every" => num(DecoratorKind::ActivateOnlyEvery),
+            "keep_activate_after_match" => DecoratorKind::KeepActivateAfterMatch,
+            "dont_activate_after_match" => DecoratorKind::DontActivateAfterMatch,
+            "depth" => num(DecoratorKind::Depth),
+            "instruct_depth" => num(DecoratorKind::InstructDepth),
+            "reverse_depth" => num(DecoratorKind::ReverseDepth),
+            "reverse_instruct_depth" => num(DecoratorKind::ReverseInstructDepth),
+            "role" if matches!(args, "assistant" | "system" | "user") => {
+                DecoratorKind::Role(args.to_string())
+            }
+            "scan_depth" => num(DecoratorKind::ScanDepth),
+            "instruct_scan_depth" => num(DecoratorKind::InstructScanDepth),
+            "is_greeting" => num(DecoratorKind::IsGreeting),
+            "position" if !args.is_empty() => DecoratorKind::Position(args.to_string()),
+            "ignore_on_max_context" => DecoratorKind::IgnoreOnMaxContext,
+            "additional_keys" => DecoratorKind::AdditionalKeys(list()),
+            "exclude_keys" => DecoratorKind::ExcludeKeys(list()),
+            "is_user_icon" => DecoratorKind::IsUserIcon(args.to_string()),
+            "activate" => DecoratorKind::Activate,
+            "dont_activate" => DecoratorKind::DontActivate,
+            "disable_ui_prompt" => DecoratorKind::DisableUiPrompt(args.to_string()),
+            _ => unknown(),
+        }
+    }
+
+    impl fmt::Display for DecoratorKind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                DecoratorKind::ActivateOnlyAfter(n) => write!(f, "第 {} 条消息之后才激活", n),
+                DecoratorKind::ActivateOnlyEvery(n) => write!(f, "每 {} 条消息激活一次", n),
+                DecoratorKind::KeepActivateAfterMatch => write!(f, "匹配后保持激活"),
+                DecoratorKind::DontActivateAfterMatch => write!(f, "匹配后不再激活"),
+                DecoratorKind::Depth(n) => write!(f, "插入深度 {}", n),
+                DecoratorKind::InstructDepth(n) => write!(f, "指令模式插入深度 {}", n),
+                DecoratorKind::ReverseDepth(n) => write!(f, "倒序插入深度 {}", n),
+                DecoratorKind::ReverseInstructDepth(n) => write!(f, "指令模式倒序插入深度 {}", n),
+                DecoratorKind::Role(r) => write!(f, "以 {} 角色插入", r),
+                DecoratorKind::ScanDepth(n) => write!(f, "扫描深度 {}", n),
+                DecoratorKind::InstructScanDepth(n) => write!(f, "指令模式扫描深度 {}", n),
+                DecoratorKind::IsGreeting(n) => write!(f, "仅在开场白 {} 时激活", n),
+                DecoratorKind::Position(p) => write!(f, "插入位置 {}", p),
+                DecoratorKind::IgnoreOnMaxContext => write!(f, "上下文已满时忽略"),
+                DecoratorKind::AdditionalKeys(k) => write!(f, "附加关键词 [{}]", k.join(", ")),
+                DecoratorKind::ExcludeKeys(k) => write!(f, "排除关键词 [{}]", k.join(", ")),
+                DecoratorKind::IsUserIcon(n) => write!(f, "用户头像为 {} 时激活", n),
+                DecoratorKind::Activate => write!(f, "总是激活"),
+                DecoratorKind::DontActivate => write!(f, "从不激活"),
+                DecoratorKind::DisableUiPrompt(p) => write!(f, "禁用界面提示词 {}", p),
+                DecoratorKind::Unknown { name, args } if args.is_empty() => {
+                    write!(f, "未知装饰器 @@{}", name)
+                }
+                DecoratorKind::Unknown { name, args } => {
+                    write!(f, "未知装饰器 @@{} {}", name, args)
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn kinds(content: &str) -> Vec<DecoratorKind> {
+            parse(content)
+                .decorators
+                .into_iter()
+                .map(|d| d.kind)
+                .collect()
+        }
+
+        fn unknown(name: &str, args: &str) -> DecoratorKind {
+            DecoratorKind::Unknown {
+                name: name.to_string(),
+                args: args.to_string(),
+            }
+        }
+
+        #[test]
+        fn known_decorators_take_their_arguments() {
+            assert_eq!(
+                kinds("@@depth 4\n@@role assistant\n@@additional_keys 猫, 狗,\n正文"),
+                [
+                    DecoratorKind::Depth(4),
+                    DecoratorKind::Role("assistant".to_string()),
+                    DecoratorKind::AdditionalKeys(vec!["猫".to_string(), "狗".to_string()]),
+                ]
+            );
+            assert_eq!(kinds("  @@activate  \n"), [DecoratorKind::Activate]);
+        }
+
+        #[test]
+        fn unknown_or_invalid_decorators_keep_their_text() {
+            assert_eq!(
+                kinds("@@depth 深\n@@role narrator\n@@position\n@@custom a b"),
+                [
+                    unknown("depth", "深"),
+                    unknown("role", "narrator"),
+                    unknown("position", ""),
+                    unknown("custom", "a b"),
+                ]
+            );
+            assert_eq!(
+                unknown("custom", "a b").to_string(),
+                "未知装饰器 @@custom a b"
+            );
+        }
+
+        #[test]
+        fn fallbacks_attach_to_the_previous_decorator() {
+            let parsed = parse("@@@depth 2\n@@role user\n@@@role system\n@@@depth 1\n正文");
+            assert_eq!(
+                parsed.decorators,
+                [
+                    // 开头的后备行没有可依附的装饰器，按普通装饰器处理
+                    Decorator {
+                        kind: DecoratorKind::Depth(2),
+                        fallbacks: Vec::new(),
+                    },
+                    Decorator {
+                        kind: DecoratorKind::Role("user".to_string()),
+                        fallbacks: vec![
+                            DecoratorKind::Role("system".to_string()),
+                            DecoratorKind::Depth(1),
+                        ],
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn body_starts_at_the_first_plain_line() {
+            let parsed = parse("@@depth 4\n第一行\n@@role user\n");
+            assert_eq!(parsed.body, "第一行\n@@role user\n");
+            assert_eq!(parsed.decorators.len(), 1);
+
+            assert_eq!(parse("@@depth 4\r\n正文").body, "正文");
+            assert_eq!(parse("@@depth 4").body, "");
+            // 空行也是正文，之后的装饰器不再解析
+            assert_eq!(parse("@@depth 4\n\n@@activate").body, "\n@@activate");
+            let plain = parse("没有装饰器 @@depth 4");
+            assert!(plain.decorators.is_empty());
+            assert_eq!(plain.body, "没有装饰器 @@depth 4");
+        }
+    }
+}
+
+mod worldinfo {
+    //! SillyTavern 独立世界书文件与角色卡内嵌世界书 (character_book) 之间的转换，
+    //! 字段对应关系与酒馆导入 / 导出角色卡时一致。
+
+    use super::types::{CharacterBook, LoreEntry, LoreExtensions, WorldEntry, WorldInfo};
+    use serde_json::{Map, Value};
+
+    /// 世界书文件 -> 内嵌世界书
+    pub fn to_character_book(world: &WorldInfo, fallback_name: &str) -> CharacterBook {
+        let mut entries: Vec<&WorldEntry> = world.entries.values().collect();
+        entries.sort_by_key(|e| e.uid);
+
+        CharacterBook {
+            name: world
+                .name
+                .clone()
+                .filter(|n| !n.trim().is_empty())
+                .unwrap_or_else(|| fallback_name.to_string()),
+            entries: entries.into_iter().map(to_lore_entry).collect(),
+            // originalData 是酒馆导入角色卡时留下的整本内嵌世界书副本，
+            // 再嵌回卡片会层层嵌套，且编辑后内容已过时
+            extra: world
+                .extra
+                .iter()
+                .filter(|(k, _)| k.as_str() != "originalData")
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn to_lore_entry(e: &WorldEntry) -> LoreEntry {
+        LoreEntry {
+            id: Some(e.uid),
+            keys: e.key.clone(),
+            secondary_keys: e.keysecondary.clone(),
+            comment: e.comment.clone(),
+            content: e.content.clone(),
+            constant: e.constant,
+            selective: e.selective,
+            insertion_order: e.order,
+            enabled: !e.disable,
+            position: if e.position == 0 {
+                "before_char".to_string()
+            } else {
+                "after_char".to_string()
+            },
+            // 世界书文件没有该开关，酒馆按 /.../ 形式自动识别正则关键词
+            use_regex: false,
+            extensions: LoreExtensions {
+                position: Some(e.position),
+                depth: Some(e.depth),
+                role: e.role,
+                display_index: e.display_index,
+                probability: Some(e.probability),
+                use_probability: Some(e.use_probability),
+                selective_logic: Some(e.selective_logic),
+                group: Some(e.group.clone()),
+                group_override: Some(e.group_override),
+                group_weight: Some(e.group_weight),
+                use_group_scoring: e.use_group_scoring,
+                sticky: e.sticky,
+                cooldown: e.cooldown,
+                delay: e.delay,
+                exclude_recursion: Some(e.exclude_recursion),
+                prevent_recursion: Some(e.prevent_recursion),
+                scan_depth: e.scan_depth,
+                match_whole_words: e.match_whole_words,
+                case_sensitive: e.case_sensitive,
+                automation_id: Some(e.automation_id.clone()),
+                vectorized: Some(e.vectorized),
+                extra: rename_keys(&e.extra, camel_to_snake),
+            },
+            extra: Map::new(),
+        }
+    }
+
+    /// 内嵌世界书 -> 世界书文件
+    pub fn from_character_book(book: &CharacterBook) -> WorldInfo {
+        // 酒馆以 uid 作为键，卡内 id 缺失或重复时改用下一个空闲编号
+        let mut used = std::collections::BTreeSet::new();
+        let entries = book
+            .entries
+            .iter()
+            .map(|entry| {
+                let mut world_entry = to_world_entry(entry);
+                let uid = world_entry.uid;
+                if used.contains(&uid) {
+                    // 先向后找，到 i32::MAX 仍被占用时从 0 开始找
+                    world_entry.uid = (uid.saturating_add(1)..=i32::MAX)
+                        .chain(0..uid)
+                        .find(|u| !used.contains(u))
+                        .unwrap_or(uid);
+                }
+                used.insert(world_entry.uid);
+                (world_entry.uid.to_string(), world_entry)
+            })
+            .collect();
+
+        WorldInfo {
+            entries,
+            name: Some(book.name.clone()).filter(|n| !n.is_empty()),
+            extra: book.extra.clone(),
+        }
+    }
+
+    fn to_world_entry(e: &LoreEntry) -> WorldEntry {
+        let ext = &e.extensions;
+        WorldEntry {
+            uid: e.id.unwrap_or(0),
+            key: e.keys.clone(),
+            keysecondary: e.secondary_keys.clone(),
+            comment: e.comment.clone(),
+            content: e.content.clone(),
+            constant: e.constant,
+            vectorized: ext.vectorized.unwrap_or(false),
+            selective: e.selective,
+            selective_logic: ext.selective_logic.unwrap_or(0),
+            order: e.insertion_order,
+            position: ext
+                .position
+                .unwrap_or(if e.position == "before_char" { 0 } else { 1 }),
+            disable: !e.enabled,
+            exclude_recursion: ext.exclude_recursion.unwrap_or(false),
+            prevent_recursion: ext.prevent_recursion.unwrap_or(false),
+            probability: ext.probability.unwrap_or(100),
+            use_probability: ext.use_probability.unwrap_or(true),
+            depth: ext.depth.unwrap_or(4),
+            group: ext.group.clone().unwrap_or_default(),
+            group_override: ext.group_override.unwrap_or(false),
+            group_weight: ext.group_weight.unwrap_or(100),
+            scan_depth: ext.scan_depth,
+            case_sensitive: ext.case_sensitive,
+            match_whole_words: ext.match_whole_words,
+            use_group_scoring: ext.use_group_scoring,
+            automation_id: ext.automation_id.clone().unwrap_or_default(),
+            role: ext.role,
+            sticky: ext.sticky,
+            cooldown: ext.cooldown,
+            delay: ext.delay,
+            display_index: ext.display_index,
+            extra: rename_keys(&ext.extra, snake_to_camel)
+                .into_iter()
+                .chain(e.extra.clone())
+                .collect(),
+        }
+    }
+
+    fn rename_keys(map: &Map<String, Value>, f: fn(&str) -> String) -> Map<String, Value> {
+        map.iter().map(|(k, v)| (f(k), v.clone())).collect()
+    }
+
+    fn camel_to_snake(key: &str) -> String {
+        let mut out = String::with_capacity(key.len() + 4);
+        for c in key.chars() {
+            if c.is_ascii_uppercase() {
+                out.push('_');
+                out.push(c.to_ascii_lowercase());
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    fn snake_to_camel(key: &str) -> String {
+        let mut out = String::with_capacity(key.len());
+        let mut upper = false;
+        for c in key.chars() {
+            if c == '_' {
+                upper = true;
+            } else if upper {
+                out.push(c.to_ascii_uppercase());
+                upper = false;
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::fixtures::{book, entry};
+
+        #[test]
+        fn duplicate_uids_get_free_numbers_without_overflow() {
+            let book = book(vec![
+                entry(i32::MAX, &["a"], ""),
+                entry(i32::MAX, &["b"], ""),
+                entry(0, &["c"], ""),
+                entry(0, &["d"], ""),
+            ]);
+            let world = from_character_book(&book);
+            let uids: Vec<i32> = world.entries.values().map(|e| e.uid).collect();
+            assert_eq!(world.entries.len(), 4);
+            assert!(uids.contains(&i32::MAX));
+            assert!(uids.contains(&0));
+            assert!(uids.contains(&1));
+            assert!(uids.contains(&2));
+        }
+
+        #[test]
+        fn original_data_is_not_embedded() {
+            let world: WorldInfo = serde_json::from_value(serde_json::json!({
+                "entries": {"0": {"uid": 0, "key": ["猫"], "content": "猫娘"}},
+                "originalData": {"name": "旧书", "entries": [{"id": 0, "keys": ["猫"]}]},
+                "custom": 1
+            }))
+            .unwrap();
+            let book = to_character_book(&world, "世界书");
+            assert!(!book.extra.contains_key("originalData"));
+            assert_eq!(book.extra["custom"], 1);
+            assert_eq!(book.entries[0].content, "猫娘");
+        }
+    }
+}
+
+mod regex_preset {
+    //! 把角色卡内的正则脚本导出为 SillyTavern 正则脚本文件 (regex-*.json)，
+    //! 所有脚本打包在一个 ZIP 中，解压后可在酒馆“正则”扩展中批量导入。
+
+    use super::types::RegexScript;
+    use anyhow::Result;
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+
+    /// 打包全部脚本，文件名沿用酒馆导出的 regex-<脚本名>.json
+    pub fn export_zip(scripts: &[RegexScript]) -> Result<Vec<u8>> {
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut zip = zip::ZipWriter::new(&mut buf);
+            let options = SimpleFileOptions::default();
+            for (i, script) in scripts.iter().enumerate() {
+                let name = script
+                    .script_name
+                    .replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "_");
+                // 序号前缀避免同名脚本互相覆盖，也保留原有顺序
+                zip.start_file(format!("{:02}_regex-{}.json", i + 1, name), options)?;
+                zip.write_all(serde_json::to_string_pretty(script)?.as_bytes())?;
+            }
+            zip.finish()?;
+        }
+        Ok(buf.into_inner())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::{Value, json};
+        use std::io::Read;
+
+        #[test]
+        fn exported_scripts_keep_every_field() {
+            let scripts: Vec<RegexScript> = serde_json::from_value(json!([
+                {
+                    "id": "a1",
+                    "scriptName": "状态栏/美化",
+                    "findRegex": "/<status>([\\s\\S]*?)<\\/status>/g",
+                    "replaceString": "<div>$1</div>",
+                    "trimStrings": ["<br>", " "],
+                    "placement": [1, 2],
+                    "substituteRegex": 2,
+                    "markdownOnly": true,
+                    "minDepth": 1,
+                    "maxDepth": -1,
+                    "promptOnly": false,
+                    "runOnEdit": true,
+                    "disabled": false,
+                    "folder": "美化"
+                },
+                { "scriptName": "状态栏/美化", "findRegex": "/x/", "placement": [2] }
+            ]))
+            .unwrap();
+
+            let bytes = export_zip(&scripts).unwrap();
+            let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+            let names: Vec<_> = (0..zip.len())
+                .map(|i| zip.by_index(i).unwrap().name().unwrap().to_string())
+                .collect();
+            // 同名脚本靠序号区分，斜杠替换为下划线
+            assert_eq!(
+                names,
+                ["01_regex-状态栏_美化.json", "02_regex-状态栏_美化.json"]
+            );
+
+            let mut read = |name: &str| -> Value {
+                let mut text = String::new();
+                zip.by_name(name)
+                    .unwrap()
+                    .read_to_string(&mut text)
+                    .unwrap();
+                serde_json::from_str(&text).unwrap()
+            };
+            let first = read("01_regex-状态栏_美化.json");
+            assert_eq!(first["scriptName"], "状态栏/美化");
+            assert_eq!(first["findRegex"], "/<status>([\\s\\S]*?)<\\/status>/g");
+            assert_eq!(first["placement"], json!([1, 2]));
+            assert_eq!(first["substituteRegex"], 2);
+            assert_eq!(first["trimStrings"], json!(["<br>", " "]));
+            assert_eq!(first["markdownOnly"], true);
+            assert_eq!(first["runOnEdit"], true);
+            assert_eq!(first["minDepth"], 1);
+            assert_eq!(first["maxDepth"], -1);
+            assert_eq!(first["folder"], "美化");
+
+            let second = read("02_regex-状态栏_美化.json");
+            assert_eq!(second["findRegex"], "/x/");
+            assert_eq!(second["placement"], json!([2]));
+        }
+    }
+}
+
+mod jsregex {
+    //! 酒馆正则脚本中的 JavaScript 正则字面量 (如 `/<status>([\s\S]*?)<\/status>/gs`)。
+    //!
+    //! 先按酒馆 `regexFromString` 的规则拆出表达式与标志，再把 JS 语法改写成
+    //! fancy-regex 能接受的等价形式后编译，用于检查脚本能否生效及后续试运行。
+
+    use super::types::RegexScript;
+    use fancy_regex::{Regex, RegexBuilder};
+
+    /// JS `\w` 只包含 ASCII 字母数字与下划线，中文不算单词字符
+    const WORD: &str = "[A-Za-z0-9_]";
+    const WORD_BOUNDARY: &str =
+        "(?:(?<=[A-Za-z0-9_])(?![A-Za-z0-9_])|(?<![A-Za-z0-9_])(?=[A-Za-z0-9_]))";
+    const NOT_WORD_BOUNDARY: &str =
+        "(?:(?<=[A-Za-z0-9_])(?=[A-Za-z0-9_])|(?<![A-Za-z0-9_])(?![A-Za-z0-9_]))";
+    /// JS 的 `.` 不匹配任何行终止符
+    const DOT: &str = r"[^\n\r\x{2028}\x{2029}]";
+
+    /// 编译结果：`regex` 为 None 时 `errors` 中给出原因
+    pub struct JsRegex {
+        pub regex: Option<Regex>,
+        /// 带 g 标志时替换全部匹配，否则只替换第一个
+        pub global: bool,
+        pub errors: Vec<String>,
+        /// 能编译，但在酒馆中的行为可能与预期不符
+        pub warnings: Vec<String>,
+    }
+
+    /// 解析并编译 `find_regex`
+    pub fn compile(literal: &str) -> JsRegex {
+        let mut out = JsRegex {
+            regex: None,
+            global: false,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        };
+        if literal.is_empty() {
+            out.errors.push("查找正则为空，脚本不会生效".to_string());
+            return out;
+        }
+
+        let (pattern, flags) = split_literal(literal);
+        match flags {
+            // 酒馆只认 gmixXsuUAJ 中不重复的标志，其余情况退回 new RegExp(原字符串)，
+            // 斜杠也成了表达式的一部分
+            Some(f) if !recognized_by_tavern(f) => {
+                out.warnings.push(format!(
+                    "标志 \"{}\" 不被酒馆识别，整个字符串 (含斜杠) 会被当作表达式",
+                    f
+                ));
+                finish(out, literal, "")
+            }
+            Some(f) => match f.chars().find(|c| !"gimsu".contains(*c)) {
+                Some(c) => {
+                    out.errors
+                        .push(format!("JavaScript 不支持标志 {}，脚本不会生效", c));
+                    out
+                }
+                None => finish(out, pattern, f),
+            },
+            None => finish(out, literal, ""),
+        }
+    }
+
+    /// 带问题的脚本：序号从 1 开始，与 TXT 中的编号一致
+    pub struct ScriptIssue {
+        pub index: usize,
+        pub name: String,
+        pub errors: Vec<String>,
+        pub warnings: Vec<String>,
+    }
+
+    /// 检查全部脚本，只返回有错误或警告的
+    pub fn check_scripts(scripts: &[RegexScript]) -> Vec<ScriptIssue> {
+        scripts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, script)| {
+                let compiled = compile(&script.find_regex);
+                (!compiled.errors.is_empty() || !compiled.warnings.is_empty()).then(|| {
+                    ScriptIssue {
+                        index: i + 1,
+                        name: script.script_name.clone(),
+                        errors: compiled.errors,
+                        warnings: compiled.warnings,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn finish(mut out: JsRegex, pattern: &str, flags: &str) -> JsRegex {
+        out.global = flags.contains('g');
+        let unicode = flags.contains('u');
+        out.warnings.extend(backtracking_risks(pattern));
+        let translated = translate(pattern, flags.contains('s'), unicode, &mut out.warnings);
+        match RegexBuilder::new(&translated)
+            .case_insensitive(flags.contains('i'))
+            .multi_line(flags.contains('m'))
+            .build()
+        {
+            Ok(re) => out.regex = Some(re),
+            Err(e) => out.errors.push(format!("无法编译: {}", e)),
+        }
+        out
+    }
+
+    /// 按酒馆规则拆分 `/pattern/flags`；不是字面量形式时 flags 为 None
+    fn split_literal(s: &str) -> (&str, Option<&str>) {
+        match s
+            .strip_prefix('/')
+            .and_then(|rest| rest.rfind('/').map(|i| (rest, i)))
+        {
+            Some((rest, i)) if i > 0 => (&rest[..i], Some(&rest[i + 1..])),
+            _ => (s, None),
+        }
+    }
+
+    fn recognized_by_tavern(flags: &str) -> bool {
+        flags
+            .char_indices()
+            .all(|(i, c)| "gmixXsuUAJ".contains(c) && !flags[..i].contains(c))
+    }
+
+    /// 把 JS 语法改写为 fancy-regex 语法，尽量保持 JS 的匹配语义
+    fn translate(
+        pattern: &str,
+        dot_all: bool,
+        unicode: bool,
+        warnings: &mut Vec<String>,
+    ) -> String {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut out = String::with_capacity(pattern.len() + 16);
+        let mut in_class = false;
+        let mut literal_p_warned = false;
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            match c {
+                '\\' => {
+                    let Some(&next) = chars.get(i + 1) else {
+                        out.push_str(r"\\");
+                        break;
+                    };
+                    i += 1;
+                    match next {
+                        'd' if in_class => out.push_str("0-9"),
+                        'w' if in_class => out.push_str("A-Za-z0-9_"),
+                        // 字符类中的 \b 是退格符
+                        'b' if in_class => out.push_str(r"\x08"),
+                        'd' => out.push_str("[0-9]"),
+                        'D' => out.push_str("[^0-9]"),
+                        'w' => out.push_str(WORD),
+                        'W' => out.push_str("[^A-Za-z0-9_]"),
+                        'b' => out.push_str(WORD_BOUNDARY),
+                        'B' => out.push_str(NOT_WORD_BOUNDARY),
+                        'c' if chars.get(i + 1).is_some_and(|l| l.is_ascii_alphabetic()) => {
+                            i += 1;
+                            out.push_str(&format!(r"\x{{{:x}}}", chars[i] as u32 % 32));
+                        }
+                        '0' if !chars.get(i + 1).is_some_and(|d| d.is_ascii_digit()) => {
+                            out.push_str(r"\x{0}")
+                        }
+                        // 没有 u 标志时 \p 只是字母 p
+                        'p' | 'P' if !unicode => {
+                            if !literal_p_warned {
+                                warnings.push(
+                                    "未使用 u 标志，\\p{...} 不是 Unicode 属性，只匹配字母 p"
+                                        .to_string(),
+                                );
+                                literal_p_warned = true;
+                            }
+                            out.push(next);
+                        }
+                        // \p{Han}、\u{4e00} 的花括号原样保留
+                        'p' | 'P' | 'u' if chars.get(i + 1) == Some(&'{') => {
+                            let close = chars[i..].iter().position(|&c| c == '}');
+                            let end = close.map_or(chars.len(), |p| i + p + 1);
+                            out.push('\\');
+                            out.extend(&chars[i..end]);
+                            i = end - 1;
+                        }
+                        '/' => out.push('/'),
+                        _ => {
+                            out.push('\\');
+                            out.push(next);
+                        }
+                    }
+                }
+                '[' if in_class => out.push_str(r"\["),
+                '&' | '~' if in_class => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                '[' => {
+                    // JS 特有的 [] (永不匹配) 与 [^] (匹配任意字符)
+                    if chars.get(i + 1) == Some(&']') {
+                        out.push_str(r"[^\s\S]");
+                        i += 1;
+                    } else if chars.get(i + 1) == Some(&'^') && chars.get(i + 2) == Some(&']') {
+                        out.push_str(r"[\s\S]");
+                        i += 2;
+                    } else {
+                        in_class = true;
+                        out.push('[');
+                        if chars.get(i + 1) == Some(&'^') {
+                            out.push('^');
+                            i += 1;
+                        }
+                    }
+                }
+                ']' if in_class => {
+                    in_class = false;
+                    out.push(']');
+                }
+                '.' if !in_class => out.push_str(if dot_all { r"[\s\S]" } else { DOT }),
+                '{' if !in_class => match quantifier_len(&chars[i..]) {
+                    Some(len) => {
+                        out.extend(&chars[i..i + len]);
+                        i += len - 1;
+                    }
+                    // 不构成量词的花括号 (如 {{char}}) 在 JS 中是普通字符
+                    None => out.push_str(r"\{"),
+                },
+                '}' if !in_class => out.push_str(r"\}"),
+                _ => out.push(c),
+            }
+            i += 1;
+        }
+        out
+    }
+
+    /// `{n}`、`{n,}`、`{n,m}` 的长度 (含花括号)；JS 不接受 `{,m}`
+    fn quantifier_len(chars: &[char]) -> Option<usize> {
+        let end = chars.iter().position(|&c| c == '}')?;
+        let body: String = chars[1..end].iter().collect();
+        let (min, max) = match body.split_once(',') {
+            Some((min, max)) => (min, Some(max)),
+            None => (body.as_str(), None),
+        };
+        let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        (digits(min) && max.is_none_or(|m| m.is_empty() || digits(m))).then_some(end + 1)
+    }
+
+    /// 在 JS 的回溯引擎中可能导致灾难性回溯的写法
+    fn backtracking_risks(pattern: &str) -> Vec<String> {
+        // 每层分组记录：起始位置、组内是否含无界量词
+        let mut groups: Vec<(usize, bool)> = Vec::new();
+        let mut risks = Vec::new();
+        let mut prev_atom: Option<(String, bool)> = None;
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let start = i;
+            let mut group_inner = None;
+            match chars[i] {
+                '\\' => i += 2,
+                '[' => {
+                    i += 1;
+                    while i < chars.len() && chars[i] != ']' {
+                        i += if chars[i] == '\\' { 2 } else { 1 };
+                    }
+                    i += 1;
+                }
+                '(' => {
+                    groups.push((i, false));
+                    prev_atom = None;
+                    i += 1;
+                    continue;
+                }
+                ')' => {
+                    let Some((open, inner)) = groups.pop() else {
+                        i += 1;
+                        continue;
+                    };
+                    group_inner = Some((open, inner));
+                    i += 1;
+                }
+                '|' => {
+                    prev_atom = None;
+                    i += 1;
+                    continue;
+                }
+                _ => i += 1,
+            }
+            let end = i.min(chars.len());
+            let (unbounded, qlen) = read_quantifier(&chars[end..]);
+            i = end + qlen;
+
+            let atom_start = group_inner.map_or(start, |(open, _)| open);
+            let atom: String = chars[atom_start..end].iter().collect();
+            if let Some((_, true)) = group_inner
+                && unbounded
+            {
+                risks.push(format!(
+                    "嵌套量词 {}{}：外层与内层都可无限重复，在不匹配的长文本上可能卡死",
+                    atom,
+                    chars[end..i].iter().collect::<String>()
+                ));
+            }
+            if unbounded
+                && let Some((prev, true)) = &prev_atom
+                && *prev == atom
+            {
+                risks.push(format!(
+                    "相邻的 {} 重复量词匹配相同内容，长文本上回溯次数成倍增加",
+                    atom
+                ));
+            }
+
+            let inner_unbounded = group_inner.is_some_and(|(_, inner)| inner);
+            if let Some(parent) = groups.last_mut() {
+                parent.1 |= unbounded || inner_unbounded;
+            }
+            prev_atom = Some((atom, unbounded));
+        }
+        risks.dedup();
+        risks
+    }
+
+    /// 紧随其后的量词：是否无上限，以及量词 (含惰性 ?) 的长度
+    fn read_quantifier(chars: &[char]) -> (bool, usize) {
+        let (unbounded, len) = match chars.first() {
+            Some('*') | Some('+') => (true, 1),
+            Some('?') => (false, 1),
+            Some('{') => match quantifier_len(chars) {
+                Some(len) => (chars[len - 2] == ',', len),
+                None => (false, 0),
+            },
+            _ => (false, 0),
+        };
+        let lazy = len > 0 && matches!(chars.get(len), Some('?') | Some('+'));
+        (unbounded, len + usize::from(lazy))
+    }
+
+    /// 酒馆正则的作用位置 (placement) 名称
+    pub fn placement_name(placement: i32) -> &'static str {
+        match placement {
+            0 => "仅显示 (旧)",
+            1 => "用户输入",
+            2 => "AI 输出",
+            3 => "斜杠命令",
+            5 => "世界书",
+            6 => "推理",
+            _ => "未知位置",
+        }
+    }
+
+    /// 脚本是否会在该作用位置、该消息深度执行；与酒馆一致，
+    /// 小于 -1 的 minDepth 与负数 maxDepth 表示不限制
+    pub fn runs_at(script: &RegexScript, placement: i32, depth: i32) -> bool {
+        !script.disabled
+            && script.placement.contains(&placement)
+            && !script.min_depth.is_some_and(|d| d >= -1 && depth < d)
+            && !script.max_depth.is_some_and(|d| d >= 0 && depth > d)
+    }
+
+    /// 按酒馆 `runRegexScript` 的规则执行一个脚本，返回替换后的文本与匹配次数
+    ///
+    /// 替换串中的 `{{match}}` 等同 `$0`，`$1`、`$<name>` 引用捕获组，插入前先删去
+    /// trimStrings；`{{char}}` / `{{user}}` 替换为角色名与 “User”，其余宏保持原样。
+    pub fn run_script(
+        script: &RegexScript,
+        text: &str,
+        char_name: &str,
+    ) -> Result<(String, usize), String> {
+        let find = match script.substitute_regex {
+            1 => substitute_macros(&script.find_regex, char_name, false),
+            2 => substitute_macros(&script.find_regex, char_name, true),
+            _ => script.find_regex.clone(),
+        };
+        let compiled = compile(&find);
+        let Some(regex) = compiled.regex else {
+            return Err(compiled.errors.join("; "));
+        };
+
+        let template = replace_ignore_case(&script.replace_string, "{{match}}", "$0");
+        let trims: Vec<String> = script
+            .trim_strings
+            .iter()
+            .map(|t| substitute_macros(t, char_name, false))
+            .collect();
+
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        let mut count = 0;
+        for caps in regex.captures_iter(text) {
+            let caps = caps.map_err(|e| format!("匹配失败: {}", e))?;
+            let whole = caps.get(0).expect("第 0 组总是存在");
+            out.push_str(&text[last..whole.start()]);
+            last = whole.end();
+            count += 1;
+
+            let replaced = expand_groups(&template, |group| {
+                let value = match group {
+                    Ok(n) => caps.get(n),
+                    Err(name) => caps.name(name),
+                };
+                value.map_or(String::new(), |m| {
+                    trims
+                        .iter()
+                        .fold(m.as_str().to_string(), |s, t| s.replace(t.as_str(), ""))
+                })
+            });
+            out.push_str(&substitute_macros(&replaced, char_name, false));
+
+            if !compiled.global {
+                break;
+            }
+        }
+        out.push_str(&text[last..]);
+        Ok((out, count))
+    }
+
+    /// 展开 `$n` 与 `$<name>`；不认识的 `$` 原样保留
+    fn expand_groups(
+        template: &str,
+        mut group: impl FnMut(Result<usize, &str>) -> String,
+    ) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(pos) = rest.find('$') {
+            out.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+            let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+            if digits > 0 {
+                out.push_str(&group(Ok(after[..digits].parse().unwrap_or(usize::MAX))));
+                rest = &after[digits..];
+            } else if let Some(name) = after
+                .strip_prefix('<')
+                .and_then(|s| s.find('>').map(|end| &s[..end]))
+                .filter(|name| !name.is_empty())
+            {
+                out.push_str(&group(Err(name)));
+                rest = &after[name.len() + 2..];
+            } else {
+                out.push('$');
+                rest = after;
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn substitute_macros(s: &str, char_name: &str, escape: bool) -> String {
+        let value = |v: &str| {
+            if escape {
+                fancy_regex::escape(v).into_owned()
+            } else {
+                v.to_string()
+            }
+        };
+        let s = replace_ignore_case(s, "{{char}}", &value(char_name));
+        replace_ignore_case(&s, "{{user}}", &value("User"))
+    }
+
+    /// 忽略 ASCII 大小写的替换，与酒馆宏的 /gi 匹配一致
+    fn replace_ignore_case(s: &str, from: &str, to: &str) -> String {
+        // ASCII 小写化不改变字节位置，可以直接用下标切原串
+        let lower = s.to_ascii_lowercase();
+        let mut out = String::with_capacity(s.len());
+        let mut last = 0;
+        for (pos, _) in lower.match_indices(from) {
+            out.push_str(&s[last..pos]);
+            out.push_str(to);
+            last = pos + from.len();
+        }
+        out.push_str(&s[last..]);
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn is_match(literal: &str, text: &str) -> bool {
+            let compiled = compile(literal);
+            assert!(compiled.errors.is_empty(), "{:?}", compiled.errors);
+            compiled.regex.unwrap().is_match(text).unwrap()
+        }
+
+        #[test]
+        fn status_block_with_dot_all() {
+            let compiled = compile(r"/<status>([\s\S]*?)<\/status>/gs");
+            assert!(compiled.global);
+            assert!(compiled.errors.is_empty() && compiled.warnings.is_empty());
+            let caps = compiled
+                .regex
+                .unwrap()
+                .captures("前<status>一\n二</status>后")
+                .unwrap()
+                .unwrap();
+            assert_eq!(&caps[1], "一\n二");
+        }
+
+        #[test]
+        fn js_only_classes() {
+            assert!(is_match(r"/a[^]b/", "a\nb"));
+            assert!(!is_match(r"/a[]b/", "ab"));
+            // 没有 s 标志时 . 不匹配换行
+            assert!(!is_match(r"/a.b/", "a\nb"));
+            assert!(is_match(r"/a.b/s", "a\nb"));
+        }
+
+        #[test]
+        fn word_boundary_is_ascii_only() {
+            // 中文不是 JS 的单词字符，与英文相邻处才有边界
+            assert!(is_match(r"/\bcat\b/", "猫cat猫"));
+            assert!(!is_match(r"/\b猫/", "一只猫"));
+            assert!(!is_match(r"/^\w+$/", "中文"));
+        }
+
+        #[test]
+        fn macro_braces_are_literal() {
+            assert!(is_match(r"/{{char}}说/", "{{char}}说"));
+            assert!(is_match(r"/a{2}/", "aa"));
+            assert!(!is_match(r"/a{2}/", "a"));
+        }
+
+        #[test]
+        fn missing_closing_slash_is_a_plain_pattern() {
+            // 酒馆退回 new RegExp(原字符串)，开头的斜杠也是表达式的一部分
+            assert!(is_match("/abc", "x/abc"));
+            assert!(!is_match("/abc", "abc"));
+        }
+
+        #[test]
+        fn flags_and_syntax_errors() {
+            let unknown = compile("/a/q");
+            assert!(unknown.errors.is_empty());
+            assert_eq!(unknown.warnings.len(), 1);
+
+            let unsupported = compile("/a/U");
+            assert!(unsupported.regex.is_none());
+            assert!(unsupported.errors[0].contains('U'));
+
+            assert!(compile("/(/").regex.is_none());
+            assert!(!compile("").errors.is_empty());
+        }
+
+        #[test]
+        fn backtracking_risks_are_reported() {
+            let nested = compile("/(a+)+$/");
+            assert!(nested.regex.is_some());
+            assert!(nested.warnings.iter().any(|w| w.contains("嵌套量词")));
+
+            let adjacent = compile(r"/\d+\d+/");
+            assert!(adjacent.warnings.iter().any(|w| w.contains("相邻")));
+
+            assert!(compile(r"/<b>(.*?)<\/b>/g").warnings.is_empty());
+        }
+
+        fn script(find: &str, replace: &str, trims: &[&str], substitute: i32) -> RegexScript {
+            RegexScript {
+                find_regex: find.to_string(),
+                replace_string: replace.to_string(),
+                trim_strings: trims.iter().map(|t| t.to_string()).collect(),
+                substitute_regex: substitute,
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn run_script_matches_tavern_output() {
+            // ((查找, 替换, trimStrings, substituteRegex), 输入, 酒馆的输出, 匹配次数)
+            type Script = (&'static str, &'static str, &'static [&'static str], i32);
+            let cases: &[(Script, &str, &str, usize)] = &[
+                (
+                    (r"/(\d+)-(\d+)/g", "$2-$1", &[], 0),
+                    "1-2 3-4",
+                    "2-1 4-3",
+                    2,
+                ),
+                ((r"/(\d+)-(\d+)/", "$2-$1", &[], 0), "1-2 3-4", "2-1 3-4", 1),
+                ((r"/(?<n>\w+)@/g", "[$<n>]", &[], 0), "a@ b@", "[a] [b]", 2),
+                (
+                    (r"/<b>.*?<\/b>/g", "【{{match}}】", &[], 0),
+                    "x<b>y</b>",
+                    "x【<b>y</b>】",
+                    1,
+                ),
+                (
+                    (r"/<b>.*?<\/b>/g", "【{{MATCH}}】", &[], 0),
+                    "<b>y</b>",
+                    "【<b>y</b>】",
+                    1,
+                ),
+                // 不存在的组替换为空，不认识的 $ 原样保留
+                ((r"/(a)/", "$1$2$ $<x>", &[], 0), "a", "a$ ", 1),
+                // trimStrings 只作用于插入的捕获内容
+                (
+                    (r"/<(.*?)>/g", "[$1]", &["嗯", "{{char}}"], 0),
+                    "<嗯好> <小明来>",
+                    "[好] [来]",
+                    2,
+                ),
+                ((r"/cat/gi", "dog", &[], 0), "Cat CAT", "dog dog", 2),
+                (
+                    (r"/x/g", "{{char}}对{{user}}", &[], 0),
+                    "x",
+                    "小明对User",
+                    1,
+                ),
+                ((r"/{{char}}/g", "他", &[], 1), "小明说", "他说", 1),
+                ((r"/{{char}}/g", "他", &[], 0), "小明说", "小明说", 0),
+            ];
+            for &((find, replace, trims, substitute), input, expected, count) in cases {
+                let case = script(find, replace, trims, substitute);
+                let (output, matched) = run_script(&case, input, "小明").unwrap();
+                assert_eq!((output.as_str(), matched), (expected, count), "{}", find);
+            }
+        }
+
+        #[test]
+        fn run_script_escapes_substituted_names() {
+            let escaped = script(r"/{{char}}/g", "他", &[], 2);
+            let (output, matched) = run_script(&escaped, "a.b 和 axb", "a.b").unwrap();
+            assert_eq!((output.as_str(), matched), ("他 和 axb", 1));
+
+            let raw = script(r"/{{char}}/g", "他", &[], 1);
+            let (output, matched) = run_script(&raw, "a.b 和 axb", "a.b").unwrap();
+            assert_eq!((output.as_str(), matched), ("他 和 他", 2));
+        }
+
+        #[test]
+        fn depth_limits_follow_tavern() {
+            let at = |min: Option<i32>, max: Option<i32>, depth: i32| {
+                let script = RegexScript {
+                    placement: vec![2],
+                    min_depth: min,
+                    max_depth: max,
+                    ..Default::default()
+                };
+                runs_at(&script, 2, depth)
+            };
+            assert!(at(None, None, 0));
+            // 负数 maxDepth 不限制
+            assert!(at(None, Some(-1), 0));
+            assert!(at(None, Some(-1), 5));
+            assert!(at(None, Some(0), 0));
+            assert!(!at(None, Some(2), 3));
+            assert!(!at(Some(1), None, 0));
+            assert!(at(Some(-1), None, 0));
+            assert!(at(Some(1), Some(-1), 1));
+
+            let disabled = RegexScript {
+                placement: vec![2],
+                disabled: true,
+                ..Default::default()
+            };
+            assert!(!runs_at(&disabled, 2, 0));
+            assert!(!runs_at(&script("/a/", "", &[], 0), 2, 0));
+        }
+
+        #[test]
+        fn run_script_reports_invalid_regex() {
+            assert!(run_script(&script("/(/", "", &[], 0), "x", "小明").is_err());
+        }
+
+        #[test]
+        fn expand_groups_table() {
+            let groups = |g: Result<usize, &str>| match g {
+                Ok(n) => format!("<{}>", n),
+                Err(name) => format!("<{}>", name),
+            };
+            for (template, expected) in [
+                ("$0", "<0>"),
+                ("$12x", "<12>x"),
+                ("$<name>!", "<name>!"),
+                ("$<>", "$<>"),
+                ("$<open", "$<open"),
+                ("a$", "a$"),
+                ("$$1", "$<1>"),
+            ] {
+                assert_eq!(expand_groups(template, groups), expected, "{}", template);
+            }
+        }
+    }
+}
+
+mod activation {
+    //! 世界书激活模拟：按酒馆的规则判断一段聊天记录会触发哪些条目，
+    //! 方便在没有酒馆的情况下调试关键词。
+    //!
+    //! 概率、分组与粘性 / 冷却依赖运行时状态或随机数，只在结果中提示，不参与判断。
+
+    use super::decorators::DecoratorKind;
+    use super::jsregex;
+    use super::types::{CharacterBook, LoreEntry};
+
+    /// 酒馆默认的扫描深度 (最近 2 条消息)
+    const DEFAULT_SCAN_DEPTH: usize = 2;
+
+    /// 条目被激活的原因
+    pub enum Reason {
+        Constant,
+        /// `@@activate` 装饰器
+        Decorator,
+        /// 在聊天记录中匹配到关键词
+        Key(String),
+        /// 在已激活条目的内容中匹配到关键词，附带该条目的序号；
+        /// 来源可能只在多个条目拼接后才命中，此时为 None
+        Recursion {
+            key: String,
+            source: Option<usize>,
+        },
+    }
+
+    pub struct Activated<'a> {
+        pub entry: &'a LoreEntry,
+        pub reason: Reason,
+        /// 注入内容 (去掉装饰器后的正文) 的估算 token 数
+        pub tokens: usize,
+    }
+
+    /// 模拟激活。`messages` 按时间顺序排列，最后一条为最新消息；
+    /// 返回的条目按 insertion_order 从小到大排列。
+    pub fn simulate<'a>(book: &'a CharacterBook, messages: &[&str]) -> Vec<Activated<'a>> {
+        let book_depth = book
+            .scan_depth
+            .and_then(|d| usize::try_from(d).ok())
+            .unwrap_or(DEFAULT_SCAN_DEPTH);
+        let recursive = book
+            .extra
+            .get("recursive_scanning")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+
+        // (条目下标, 原因)，激活顺序即递归的来源顺序
+        let mut activated: Vec<(usize, Reason)> = Vec::new();
+        let mut recursion_buffer = String::new();
+        let mut first_pass = true;
+
+        loop {
+            let mut new = Vec::new();
+            for (i, entry) in book.entries.iter().enumerate() {
+                if !entry.enabled || activated.iter().any(|(a, _)| *a == i) {
+                    continue;
+                }
+                let decorators = entry.decorated_content().decorators;
+                let has = |f: fn(&DecoratorKind) -> bool| decorators.iter().any(|d| f(&d.kind));
+                if has(|k| matches!(k, DecoratorKind::DontActivate)) {
+                    continue;
+                }
+                // 聊天记录不足 delay 条时不会激活
+                if entry
+                    .extensions
+                    .delay
+                    .is_some_and(|d| d > 0 && messages.len() < d as usize)
+                {
+                    continue;
+                }
+
+                if first_pass {
+                    if entry.constant {
+                        new.push((i, Reason::Constant));
+                        continue;
+                    }
+                    if has(|k| matches!(k, DecoratorKind::Activate)) {
+                        new.push((i, Reason::Decorator));
+                        continue;
+                    }
+                }
+
+                let depth = decorators
+                    .iter()
+                    .find_map(|d| match d.kind {
+                        DecoratorKind::ScanDepth(n) => Some(n as usize),
+                        _ => None,
+                    })
+                    .or_else(|| {
+                        entry
+                            .extensions
+                            .scan_depth
+                            .and_then(|d| usize::try_from(d).ok())
+                    })
+                    .unwrap_or(book_depth);
+                let chat = messages[messages.len().saturating_sub(depth)..].join("\n");
+
+                let haystack = if first_pass {
+                    chat
+                } else if entry.extensions.exclude_recursion == Some(true) {
+                    continue;
+                } else {
+                    format!("{}\n{}", chat, recursion_buffer)
+                };
+                if let Some(key) = entry_matches(entry, &decorators, &haystack) {
+                    let reason = if first_pass {
+                        Reason::Key(key)
+                    } else {
+                        let source = source_of(book, &activated, &key, entry);
+                        Reason::Recursion { key, source }
+                    };
+                    new.push((i, reason));
+                }
+            }
+
+            if new.is_empty() {
+                break;
+            }
+            for (i, _) in &new {
+                let entry = &book.entries[*i];
+                if entry.extensions.prevent_recursion != Some(true) {
+                    recursion_buffer.push('\n');
+                    recursion_buffer.push_str(entry.decorated_content().body);
+                }
+            }
+            activated.extend(new);
+            if !recursive {
+                break;
+            }
+            first_pass = false;
+        }
+
+        let mut result: Vec<Activated> = activated
+            .into_iter()
+            .map(|(i, reason)| {
+                let entry = &book.entries[i];
+                Activated {
+                    entry,
+                    reason,
+                    tokens: estimate_tokens(entry.decorated_content().body),
+                }
+            })
+            .collect();
+        result.sort_by_key(|a| a.entry.insertion_order);
+        result
+    }
+
+    /// 粗略估算 token：CJK 等非 ASCII 字符约每字 1 个，ASCII 约每 4 字符 1 个
+    pub fn estimate_tokens(text: &str) -> usize {
+        let ascii = text.chars().filter(char::is_ascii).count();
+        text.chars().count() - ascii + ascii.div_ceil(4)
+    }
+
+    /// 判断条目能否被 `haystack` 激活，返回命中的主关键词
+    fn entry_matches(
+        entry: &LoreEntry,
+        decorators: &[super::decorators::Decorator],
+        haystack: &str,
+    ) -> Option<String> {
+        let mut keys: Vec<&String> = entry.keys.iter().collect();
+        let mut exclude: Vec<&String> = Vec::new();
+        for d in decorators {
+            match &d.kind {
+                DecoratorKind::AdditionalKeys(k) => keys.extend(k),
+                DecoratorKind::ExcludeKeys(k) => exclude.extend(k),
+                _ => {}
+            }
+        }
+
+        let case_sensitive = entry.extensions.case_sensitive.unwrap_or_else(|| {
+            entry
+                .extra
+                .get("case_sensitive")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false)
+        });
+        let whole_words = entry.extensions.match_whole_words.unwrap_or(false);
+        let hit =
+            |key: &str| key_matches(key, haystack, case_sensitive, whole_words, entry.use_regex);
+
+        let key = keys.into_iter().find(|k| !k.trim().is_empty() && hit(k))?;
+        if exclude.iter().any(|k| hit(k)) {
+            return None;
+        }
+
+        let secondary: Vec<&String> = entry
+            .secondary_keys
+            .iter()
+            .filter(|k| !k.trim().is_empty())
+            .collect();
+        if entry.selective && !secondary.is_empty() {
+            let matched = secondary.iter().filter(|k| hit(k)).count();
+            let passed = match entry.extensions.selective_logic.unwrap_or(0) {
+                1 => matched < secondary.len(),
+                2 => matched == 0,
+                3 => matched == secondary.len(),
+                _ => matched > 0,
+            };
+            if !passed {
+                return None;
+            }
+        }
+        Some(key.clone())
+    }
+
+    /// 与酒馆 `matchKeys` 一致：`/…/flags` 形式或开启 use_regex 时按正则匹配，
+    /// 否则按子串匹配；全词匹配时单个词两侧不能是 ASCII 单词字符
+    fn key_matches(
+        key: &str,
+        haystack: &str,
+        case_sensitive: bool,
+        whole_words: bool,
+        use_regex: bool,
+    ) -> bool {
+        let key = key.trim();
+        let is_literal = key.len() > 2 && key.starts_with('/') && key[1..].contains('/');
+        if is_literal || use_regex {
+            let literal = if is_literal {
+                key.to_string()
+            } else {
+                format!("/{}/{}", key, if case_sensitive { "" } else { "i" })
+            };
+            return jsregex::compile(&literal)
+                .regex
+                .is_some_and(|re| re.is_match(haystack).unwrap_or(false));
+        }
+
+        let (haystack, key) = if case_sensitive {
+            (haystack.to_string(), key.to_string())
+        } else {
+            (haystack.to_lowercase(), key.to_lowercase())
+        };
+        if !whole_words || key.contains(char::is_whitespace) {
+            return haystack.contains(&key);
+        }
+        let is_word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+        haystack.match_indices(&key).any(|(pos, _)| {
+            !is_word(haystack[..pos].chars().next_back())
+                && !is_word(haystack[pos + key.len()..].chars().next())
+        })
+    }
+
+    /// 找出内容中含有该关键词的已激活条目，作为递归来源
+    fn source_of(
+        book: &CharacterBook,
+        activated: &[(usize, Reason)],
+        key: &str,
+        entry: &LoreEntry,
+    ) -> Option<usize> {
+        activated.iter().map(|(i, _)| *i).find(|&i| {
+            let source = &book.entries[i];
+            source.extensions.prevent_recursion != Some(true)
+                && entry_matches(
+                    entry,
+                    &entry.decorated_content().decorators,
+                    source.decorated_content().body,
+                )
+                .is_some_and(|k| k == key)
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::fixtures::{book, entry};
+
+        fn ids(activated: &[Activated]) -> Vec<i32> {
+            activated.iter().filter_map(|a| a.entry.id).collect()
+        }
+
+        #[test]
+        fn primary_keys_within_scan_depth() {
+            let mut disabled = entry(3, &["猫"], "");
+            disabled.enabled = false;
+            let mut constant = entry(4, &[], "");
+            constant.constant = true;
+            let book = book(vec![
+                entry(1, &["猫"], ""),
+                entry(2, &["狗"], ""),
+                disabled,
+                constant,
+            ]);
+
+            let activated = simulate(&book, &["狗", "旧消息", "一只猫"]);
+            assert_eq!(ids(&activated), [1, 4]);
+            assert!(matches!(&activated[0].reason, Reason::Key(k) if k == "猫"));
+            assert!(matches!(activated[1].reason, Reason::Constant));
+        }
+
+        #[test]
+        fn secondary_key_logic() {
+            // (selectiveLogic, 能否激活)
+            for (logic, expected) in [(0, true), (1, true), (2, false), (3, false)] {
+                let mut e = entry(1, &["猫"], "");
+                e.selective = true;
+                e.secondary_keys = vec!["黑".to_string(), "白".to_string()];
+                e.extensions.selective_logic = Some(logic);
+                let book = book(vec![e]);
+                let activated = simulate(&book, &["一只黑猫"]);
+                assert_eq!(!activated.is_empty(), expected, "logic {}", logic);
+            }
+        }
+
+        #[test]
+        fn recursion_records_its_source() {
+            let book = book(vec![
+                entry(1, &["城堡"], "城堡里住着国王"),
+                entry(2, &["国王"], "国王有一把剑"),
+                entry(3, &["剑"], "剑名为月光"),
+            ]);
+            let activated = simulate(&book, &["去城堡"]);
+            assert_eq!(ids(&activated), [1, 2, 3]);
+            assert!(
+                matches!(&activated[1].reason, Reason::Recursion { key, source: Some(0) } if key == "国王")
+            );
+            assert!(
+                matches!(&activated[2].reason, Reason::Recursion { key, source: Some(1) } if key == "剑")
+            );
+        }
+
+        #[test]
+        fn prevent_recursion_stops_the_chain() {
+            let mut castle = entry(1, &["城堡"], "城堡里住着国王");
+            castle.extensions.prevent_recursion = Some(true);
+            let prevented = book(vec![castle, entry(2, &["国王"], "")]);
+            assert_eq!(ids(&simulate(&prevented, &["去城堡"])), [1]);
+
+            let mut king = entry(2, &["国王"], "");
+            king.extensions.exclude_recursion = Some(true);
+            let excluded = book(vec![entry(1, &["城堡"], "城堡里住着国王"), king]);
+            assert_eq!(ids(&simulate(&excluded, &["去城堡"])), [1]);
+        }
+
+        #[test]
+        fn recursion_without_a_single_source() {
+            // 主关键词与次要关键词分别来自两个条目，单看任一条目都不满足
+            let mut both = entry(3, &["国王"], "");
+            both.selective = true;
+            both.secondary_keys = vec!["剑".to_string()];
+            both.extensions.selective_logic = Some(3);
+            let book = book(vec![
+                entry(1, &["甲"], "国王"),
+                entry(2, &["乙"], "剑"),
+                both,
+            ]);
+
+            let activated = simulate(&book, &["甲乙"]);
+            assert_eq!(ids(&activated), [1, 2, 3]);
+            assert!(matches!(
+                activated[2].reason,
+                Reason::Recursion { source: None, .. }
+            ));
+        }
+    }
+}
+
+mod diagnostics {
+    //! 世界书诊断：找出永远不会触发、互相遮蔽或重复的条目，
+    //! 大型中文世界书里这类问题很难靠肉眼发现。
+
+    use super::decorators::DecoratorKind;
+    use super::types::{CharacterBook, LoreEntry};
+    use std::collections::{BTreeMap, HashMap};
+
+    /// 每类问题最多列出的条数，避免报告比世界书本身还长
+    const MAX_ITEMS: usize = 50;
+
+    /// 生成 “世界书诊断” 章节
+    pub fn report(book: &CharacterBook) -> String {
+        let entries = &book.entries;
+        let mut s = String::new();
+        s.push_str(&format!("\n{}\n", "-".repeat(40)));
+        s.push_str("【世界书诊断】\n");
+        s.push_str(&format!(
+            "共 {} 条，启用 {} 条，常驻 {} 条\n",
+            entries.len(),
+            entries.iter().filter(|e| e.enabled).count(),
+            entries.iter().filter(|e| e.enabled && e.constant).count()
+        ));
+
+        let mut sections = 0;
+        let mut section = |title: &str, items: Vec<String>| {
+            if items.is_empty() {
+                return;
+            }
+            sections += 1;
+            s.push_str(&format!("\n■ {} — {} 项\n", title, items.len()));
+            for item in items.iter().take(MAX_ITEMS) {
+                s.push_str(&format!("  - {}\n", item));
+            }
+            if items.len() > MAX_ITEMS {
+                s.push_str(&format!("  … 另有 {} 项未列出\n", items.len() - MAX_ITEMS));
+            }
+        };
+
+        section(
+            "永远不会触发 (没有关键词，也不是常驻)",
+            entries
+                .iter()
+                .filter(|e| e.enabled && !e.constant && keys_of(e).is_empty() && !forced(e))
+                .map(|e| e.display_name())
+                .collect(),
+        );
+        section("重复的 id", duplicate_ids(entries));
+        section(
+            "已禁用的条目",
+            entries
+                .iter()
+                .filter(|e| !e.enabled)
+                .map(|e| e.display_name())
+                .collect(),
+        );
+        section(
+            "内容为空",
+            entries
+                .iter()
+                .filter(|e| e.enabled && e.decorated_content().body.trim().is_empty())
+                .map(|e| e.display_name())
+                .collect(),
+        );
+        section(
+            "关键词遮蔽 (长关键词出现时，包含它的短关键词条目必然一同触发)",
+            shadowed_keys(entries),
+        );
+        section("多个条目共用的关键词", shared_keys(entries));
+
+        if sections == 0 {
+            s.push_str("\n未发现问题\n");
+        }
+        s
+    }
+
+    /// 条目的有效主关键词 (去空白、统一小写)；`/正则/` 关键词不参与包含关系比较
+    fn keys_of(entry: &LoreEntry) -> Vec<String> {
+        let mut keys: Vec<String> = entry
+            .keys
+            .iter()
+            .map(|k| k.trim().to_lowercase())
+            .filter(|k| !k.is_empty())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// `@@activate` 或 `@@additional_keys` 让没有关键词的条目也能触发
+    fn forced(entry: &LoreEntry) -> bool {
+        entry.decorated_content().decorators.iter().any(|d| {
+            matches!(
+                d.kind,
+                DecoratorKind::Activate | DecoratorKind::AdditionalKeys(_)
+            )
+        })
+    }
+
+    fn is_regex_key(key: &str) -> bool {
+        key.len() > 2 && key.starts_with('/') && key[1..].contains('/')
+    }
+
+    fn duplicate_ids(entries: &[LoreEntry]) -> Vec<String> {
+        let mut by_id: BTreeMap<i32, Vec<String>> = BTreeMap::new();
+        for e in entries {
+            if let Some(id) = e.id {
+                by_id.entry(id).or_default().push(e.display_name());
+            }
+        }
+        by_id
+            .into_iter()
+            .filter(|(_, names)| names.len() > 1)
+            .map(|(id, names)| format!("id {}: {}", id, names.join("、")))
+            .collect()
+    }
+
+    fn shared_keys(entries: &[LoreEntry]) -> Vec<String> {
+        let mut by_key: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for e in entries.iter().filter(|e| e.enabled) {
+            for key in keys_of(e) {
+                by_key.entry(key).or_default().push(e.display_name());
+            }
+        }
+        by_key
+            .into_iter()
+            .filter(|(_, names)| names.len() > 1)
+            .map(|(key, names)| format!("「{}」: {}", key, names.join("、")))
+            .collect()
+    }
+
+    /// 关键词 A 是另一条目关键词 B 的子串时，B 出现必然触发 A 的条目
+    ///
+    /// 按关键词建索引，只查长关键词的子串，避免大型世界书中两两比较全部关键词
+    fn shadowed_keys(entries: &[LoreEntry]) -> Vec<String> {
+        let keyed: Vec<(&LoreEntry, Vec<String>)> = entries
+            .iter()
+            .filter(|e| e.enabled && !e.constant)
+            .map(|e| {
+                let keys = keys_of(e)
+                    .into_iter()
+                    .filter(|k| !is_regex_key(k))
+                    .collect();
+                (e, keys)
+            })
+            .collect();
+
+        let mut owners: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, (_, keys)) in keyed.iter().enumerate() {
+            for key in keys {
+                owners.entry(key.as_str()).or_default().push(i);
+            }
+        }
+
+        // 比最长的关键词还长的子串不可能命中
+        let max_len = owners.keys().map(|k| k.len()).max().unwrap_or(0);
+
+        // (短关键词条目, 长关键词条目, 短关键词) → 第一个包含它的长关键词
+        let mut found: BTreeMap<(usize, usize, &str), &str> = BTreeMap::new();
+        for (j, (_, long_keys)) in keyed.iter().enumerate() {
+            for long in long_keys {
+                let bounds: Vec<usize> = long
+                    .char_indices()
+                    .map(|(pos, _)| pos)
+                    .chain([long.len()])
+                    .collect();
+                for (n, &start) in bounds.iter().enumerate() {
+                    for &end in &bounds[n + 1..] {
+                        if end - start > max_len {
+                            break;
+                        }
+                        let short = &long[start..end];
+                        if short.len() == long.len() {
+                            continue;
+                        }
+                        let Some(owners) = owners.get(short) else {
+                            continue;
+                        };
+                        // 两个条目本身就共用这个短关键词时，归入“共用的关键词”
+                        if long_keys.iter().any(|k| k == short) {
+                            continue;
+                        }
+                        for &i in owners.iter().filter(|&&i| i != j) {
+                            found.entry((i, j, short)).or_insert(long.as_str());
+                        }
+                    }
+                }
+            }
+        }
+
+        found
+            .into_iter()
+            .map(|((i, j, short), long)| {
+                format!(
+                    "「{}」⊂「{}」: {} 会随 {} 一同触发",
+                    short,
+                    long,
+                    keyed[i].0.display_name(),
+                    keyed[j].0.display_name()
+                )
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use kovi::serde_json;
+
+        fn book(entries: &str) -> CharacterBook {
+            serde_json::from_str(&format!(r#"{{"entries":{}}}"#, entries)).unwrap()
+        }
+
+        #[test]
+        fn missing_ids_are_not_duplicates() {
+            let book = book(
+                r#"[{"keys":["a"],"enabled":true},{"keys":["b"],"enabled":true},
+                    {"id":3,"keys":["c"],"enabled":true},{"id":3,"keys":["d"],"enabled":true}]"#,
+            );
+            assert_eq!(duplicate_ids(&book.entries), ["id 3: c、d"]);
+        }
+
+        #[test]
+        fn shadowed_keys_by_substring() {
+            let book = book(
+                r#"[{"comment":"猫","keys":["猫"],"enabled":true},
+                    {"comment":"黑猫","keys":["黑猫","猫"],"enabled":true},
+                    {"comment":"黑猫警长","keys":["黑猫警长"],"enabled":true},
+                    {"comment":"常驻","keys":["警"],"enabled":true,"constant":true}]"#,
+            );
+            assert_eq!(
+                shadowed_keys(&book.entries),
+                [
+                    "「猫」⊂「黑猫警长」: 猫 会随 黑猫警长 一同触发",
+                    "「猫」⊂「黑猫警长」: 黑猫 会随 黑猫警长 一同触发",
+                    "「黑猫」⊂「黑猫警长」: 黑猫 会随 黑猫警长 一同触发",
+                ]
+            );
+        }
+
+        #[test]
+        fn large_books_stay_fast() {
+            let entries: Vec<String> = (0..5000)
+                .map(|i| format!(r#"{{"keys":["角色{}号","地点{}"],"enabled":true}}"#, i, i))
+                .collect();
+            let book = book(&format!("[{}]", entries.join(",")));
+            let started = std::time::Instant::now();
+            let items = shadowed_keys(&book.entries);
+            assert!(started.elapsed().as_secs() < 5);
+            // 「地点1」⊂「地点10」等
+            assert!(items.iter().any(|i| i.starts_with("「地点1」⊂「地点10」")));
+        }
+    }
+}
+
+mod divergence {
+    //! 同一张图片中 ccv3 与 chara 两份数据的差异。
+    //!
+    //! 有的编辑器只改其中一份：酒馆读 ccv3，只支持 V2 的前端读 chara，
+    //! 两边看到的就是两个不同的角色。这里只比较 V2 规范中也有的字段，
+    //! nickname、assets 等 V3 独有字段本来就不会写进 chara，不算差异。
+
+    use super::types::{CharacterBook, CharacterData};
+    use kovi::serde_json;
+    use serde::Serialize;
+
+    /// 一处不一致：字段名与两边的对比说明
+    pub struct Difference {
+        pub field: &'static str,
+        pub detail: String,
+    }
+
+    /// 比较两份数据，相同时返回空列表
+    pub fn compare(ccv3: &CharacterData, chara: &CharacterData) -> Vec<Difference> {
+        let mut diffs = Vec::new();
+        let texts = [
+            ("名称", &ccv3.name, &chara.name),
+            ("描述", &ccv3.description, &chara.description),
+            ("性格", &ccv3.personality, &chara.personality),
+            ("场景", &ccv3.scenario, &chara.scenario),
+            ("开场白", &ccv3.first_mes, &chara.first_mes),
+            ("对话示例", &ccv3.mes_example, &chara.mes_example),
+            ("系统提示词", &ccv3.system_prompt, &chara.system_prompt),
+            (
+                "历史后指令",
+                &ccv3.post_history_instructions,
+                &chara.post_history_instructions,
+            ),
+            ("作者注释", &ccv3.creator_notes, &chara.creator_notes),
+            ("作者", &ccv3.creator, &chara.creator),
+            ("版本", &ccv3.character_version, &chara.character_version),
+        ];
+        for (field, a, b) in texts {
+            if let Some(detail) = text_diff(a, b) {
+                diffs.push(Difference { field, detail });
+            }
+        }
+
+        if let Some(detail) = list_diff(&ccv3.alternate_greetings, &chara.alternate_greetings) {
+            diffs.push(Difference {
+                field: "备选开场白",
+                detail,
+            });
+        }
+        if let Some(detail) = tag_diff(&ccv3.tags, &chara.tags) {
+            diffs.push(Difference {
+                field: "标签",
+                detail,
+            });
+        }
+        if let Some(detail) = book_diff(ccv3.character_book.as_ref(), chara.character_book.as_ref())
+        {
+            diffs.push(Difference {
+                field: "世界书",
+                detail,
+            });
+        }
+
+        let scripts = |c: &CharacterData| {
+            c.extensions
+                .as_ref()
+                .map(|e| e.regex_scripts.clone())
+                .unwrap_or_default()
+        };
+        if let Some(detail) = list_diff(&scripts(ccv3), &scripts(chara)) {
+            diffs.push(Difference {
+                field: "正则脚本",
+                detail,
+            });
+        }
+        let depth_prompt = |c: &CharacterData| {
+            c.extensions
+                .as_ref()
+                .and_then(|e| e.depth_prompt.as_ref())
+                .map(|d| d.prompt.clone())
+                .unwrap_or_default()
+        };
+        if let Some(detail) = text_diff(&depth_prompt(ccv3), &depth_prompt(chara)) {
+            diffs.push(Difference {
+                field: "角色备注 (深度提示词)",
+                detail,
+            });
+        }
+        diffs
+    }
+
+    /// 生成 TXT 中的差异章节
+    pub fn report(diffs: &[Difference]) -> String {
+        let mut s = String::new();
+        s.push_str(&format!("\n{}\n", "-".repeat(40)));
+        s.push_str("【ccv3 / chara 差异】\n");
+        s.push_str("酒馆读取 ccv3，只支持 V2 的前端读取 chara，以下字段两者不一致：\n");
+        for d in diffs {
+            s.push_str(&format!("  - {}: {}\n", d.field, d.detail));
+        }
+        s
+    }
+
+    /// 短文本直接列出两边的值，长文本只比较字数
+    fn text_diff(a: &str, b: &str) -> Option<String> {
+        if a == b {
+            return None;
+        }
+        let short = |s: &str| s.chars().count() <= 20 && !s.contains('\n');
+        Some(if a.trim().is_empty() {
+            "ccv3 中为空".to_string()
+        } else if b.trim().is_empty() {
+            "chara 中为空".to_string()
+        } else if short(a) && short(b) {
+            format!("ccv3「{}」，chara「{}」", a, b)
+        } else if a.trim() == b.trim() {
+            "仅首尾空白不同".to_string()
+        } else {
+            format!(
+                "内容不同 (ccv3 {} 字，chara {} 字)",
+                a.chars().count(),
+                b.chars().count()
+            )
+        })
+    }
+
+    fn list_diff<T: Serialize>(a: &[T], b: &[T]) -> Option<String> {
+        if a.len() != b.len() {
+            return Some(format!("ccv3 {} 条，chara {} 条", a.len(), b.len()));
+        }
+        let changed = a.iter().zip(b).filter(|(x, y)| !same(x, y)).count();
+        (changed > 0).then(|| format!("{} 条内容不同", changed))
+    }
+
+    fn tag_diff(a: &[String], b: &[String]) -> Option<String> {
+        let only = |x: &[String], y: &[String]| {
+            x.iter()
+                .filter(|t| !y.contains(t))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let (only_a, only_b) = (only(a, b), only(b, a));
+        let mut parts = Vec::new();
+        if !only_a.is_empty() {
+            parts.push(format!("仅 ccv3 有 {}", only_a.join("、")));
+        }
+        if !only_b.is_empty() {
+            parts.push(format!("仅 chara 有 {}", only_b.join("、")));
+        }
+        (!parts.is_empty()).then(|| parts.join("；"))
+    }
+
+    fn book_diff(a: Option<&CharacterBook>, b: Option<&CharacterBook>) -> Option<String> {
+        match (a, b) {
+            (None, None) => None,
+            (Some(a), None) if !a.entries.is_empty() => {
+                Some(format!("仅 ccv3 中存在 ({} 条)", a.entries.len()))
+            }
+            (None, Some(b)) if !b.entries.is_empty() => {
+                Some(format!("仅 chara 中存在 ({} 条)", b.entries.len()))
+            }
+            (Some(a), Some(b)) => list_diff(&a.entries, &b.entries).map(|d| format!("条目 {}", d)),
+            _ => None,
+        }
+    }
+
+    /// 按序列化结果比较，类型上不需要实现 PartialEq
+    fn same<T: Serialize>(a: &T, b: &T) -> bool {
+        serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+    }
+}
+
+mod sheet {
+    //! 世界书条目与表格 (CSV / XLSX) 互转：每行一个条目，
+    //! 编辑后的表格按 id 合并回世界书，表格中没有的列保持原值。
+
+    use super::types::{CharacterBook, LoreEntry};
+    use anyhow::{Result, anyhow};
+    use calamine::{Reader, Xlsx};
+    use kovi::serde_json;
+    use rust_xlsxwriter::{Format, Workbook};
+    use std::io::Cursor;
+
+    /// 导出的表头；导入时也接受英文字段名，见 `Column::from_header`
+    const HEADERS: [&str; 10] = [
+        "id",
+        "关键词",
+        "次要关键词",
+        "注释",
+        "内容",
+        "启用",
+        "常驻",
+        "选择性",
+        "顺序",
+        "位置",
+    ];
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Column {
+        Id,
+        Keys,
+        SecondaryKeys,
+        Comment,
+        Content,
+        Enabled,
+        Constant,
+        Selective,
+        Order,
+        Position,
+    }
+
+    impl Column {
+        fn from_header(header: &str) -> Option<Self> {
+            Some(match header.trim().to_lowercase().as_str() {
+                "id" | "uid" => Column::Id,
+                "关键词" | "主关键词" | "keys" | "key" => Column::Keys,
+                "次要关键词" | "secondary_keys" | "keysecondary" => Column::SecondaryKeys,
+                "注释" | "备注" | "comment" => Column::Comment,
+                "内容" | "content" => Column::Content,
+                "启用" | "enabled" => Column::Enabled,
+                "常驻" | "constant" => Column::Constant,
+                "选择性" | "selective" => Column::Selective,
+                "顺序" | "插入顺序" | "insertion_order" | "order" => Column::Order,
+                "位置" | "position" => Column::Position,
+                _ => return None,
+            })
+        }
+    }
+
+    /// 合并结果统计
+    pub struct MergeSummary {
+        pub updated: usize,
+        pub added: usize,
+    }
+
+    fn entry_cells(entry: &LoreEntry) -> [String; 10] {
+        let flag = |b: bool| if b { "是" } else { "否" }.to_string();
+        [
+            entry.id.map_or(String::new(), |id| id.to_string()),
+            join_keys(&entry.keys),
+            join_keys(&entry.secondary_keys),
+            entry.comment.clone(),
+            entry.content.clone(),
+            flag(entry.enabled),
+            flag(entry.constant),
+            flag(entry.selective),
+            entry.insertion_order.to_string(),
+            entry.position.clone(),
+        ]
+    }
+
+    /// 导出 CSV，带 UTF-8 BOM 以便 Excel 直接打开不乱码
+    pub fn export_csv(book: &CharacterBook) -> Result<Vec<u8>> {
+        let mut writer = csv::Writer::from_writer(vec![0xEF, 0xBB, 0xBF]);
+        writer.write_record(HEADERS)?;
+        for entry in &book.entries {
+            writer.write_record(entry_cells(entry))?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| anyhow!("CSV 写入失败: {}", e.error()))
+    }
+
+    /// Excel 单元格最多容纳的字符数，rust_xlsxwriter 会拒绝更长的字符串
+    pub const XLSX_CELL_LIMIT: usize = 32767;
+
+    /// 导出 XLSX；有单元格超过 [`XLSX_CELL_LIMIT`] 时报错，由调用方改用 CSV
+    pub fn export_xlsx(book: &CharacterBook) -> Result<Vec<u8>> {
+        let mut workbook = Workbook::new();
+        let sheet = workbook.add_worksheet();
+        sheet.set_name("世界书")?;
+
+        let bold = Format::new().set_bold();
+        let wrap = Format::new().set_text_wrap();
+        for (col, header) in HEADERS.iter().enumerate() {
+            sheet.write_string_with_format(0, col as u16, *header, &bold)?;
+        }
+        for (row, entry) in book.entries.iter().enumerate() {
+            let row = row as u32 + 1;
+            for (col, cell) in entry_cells(entry).iter().enumerate() {
+                if cell.chars().count() > XLSX_CELL_LIMIT {
+                    return Err(anyhow!(
+                        "条目 {} 的“{}”超过 XLSX 单元格上限 {} 字符",
+                        entry.display_name(),
+                        HEADERS[col],
+                        XLSX_CELL_LIMIT
+                    ));
+                }
+                match col {
+                    // id 与顺序写成数字，方便排序
+                    0 | 8 if !cell.is_empty() => {
+                        sheet.write_number(row, col as u16, cell.parse::<f64>()?)?
+                    }
+                    4 => sheet.write_string_with_format(row, col as u16, cell, &wrap)?,
+                    _ => sheet.write_string(row, col as u16, cell)?,
+                };
+            }
+        }
+        sheet.set_column_width(1, 20)?;
+        sheet.set_column_width(3, 20)?;
+        sheet.set_column_width(4, 80)?;
+        sheet.set_freeze_panes(1, 0)?;
+        Ok(workbook.save_to_buffer()?)
+    }
+
+    /// 读取表格的全部单元格；XLSX 取第一个工作表，其余按 CSV 读取
+    pub fn read(bytes: &[u8]) -> Result<Vec<Vec<String>>> {
+        if bytes.starts_with(b"PK\x03\x04") {
+            let mut workbook: Xlsx<_> = calamine::open_workbook_from_rs(Cursor::new(bytes))
+                .map_err(|e| anyhow!("XLSX 读取失败: {}", e))?;
+            let range = workbook
+                .worksheet_range_at(0)
+                .ok_or_else(|| anyhow!("XLSX 中没有工作表"))?
+                .map_err(|e| anyhow!("XLSX 读取失败: {}", e))?;
+            return Ok(range
+                .rows()
+                .map(|row| row.iter().map(|c| c.to_string()).collect())
+                .collect());
+        }
+
+        let text = std::str::from_utf8(bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(bytes))
+            .map_err(|_| anyhow!("表格不是 UTF-8 编码的 CSV，请另存为 “CSV UTF-8” 或 XLSX"))?;
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(text.as_bytes());
+        reader
+            .records()
+            .map(|r| {
+                r.map(|rec| rec.iter().map(str::to_string).collect())
+                    .map_err(|e| anyhow!("CSV 解析失败: {}", e))
+            })
+            .collect()
+    }
+
+    /// 把表格合并进世界书：id 相同的条目更新表格中出现的列，其余行作为新条目追加；
+    /// 表格中没有的条目保持不变
+    pub fn merge(book: &mut CharacterBook, rows: &[Vec<String>]) -> Result<MergeSummary> {
+        let (header, rows) = rows.split_first().ok_or_else(|| anyhow!("表格是空的"))?;
+        let columns: Vec<Option<Column>> = header.iter().map(|h| Column::from_header(h)).collect();
+        if !columns
+            .iter()
+            .any(|c| matches!(c, Some(Column::Keys) | Some(Column::Content)))
+        {
+            return Err(anyhow!(
+                "表格第一行需要是表头，至少包含“关键词”或“内容”列 (可先用 读卡 表格 导出模板)"
+            ));
+        }
+
+        let mut summary = MergeSummary {
+            updated: 0,
+            added: 0,
+        };
+        for row in rows {
+            if row.iter().all(|c| c.trim().is_empty()) {
+                continue;
+            }
+            let cell = |col: Column| {
+                columns
+                    .iter()
+                    .position(|c| *c == Some(col))
+                    .map(|i| row.get(i).map_or("", |s| s.as_str()))
+            };
+            let id = cell(Column::Id).and_then(parse_int);
+
+            let index = match id.and_then(|id| book.entries.iter().position(|e| e.id == Some(id))) {
+                Some(i) => {
+                    summary.updated += 1;
+                    i
+                }
+                None => {
+                    let next_id = match book.entries.iter().filter_map(|e| e.id).max() {
+                        None => 0,
+                        Some(max) => max.checked_add(1).unwrap_or_else(|| {
+                            // 已有 i32::MAX 时从 0 开始找未用的 id
+                            (0..i32::MAX)
+                                .find(|id| book.entries.iter().all(|e| e.id != Some(*id)))
+                                .unwrap_or(max)
+                        }),
+                    };
+                    book.entries.push(LoreEntry {
+                        id: Some(id.unwrap_or(next_id)),
+                        enabled: true,
+                        insertion_order: 100,
+                        position: "before_char".to_string(),
+                        ..Default::default()
+                    });
+                    summary.added += 1;
+                    book.entries.len() - 1
+                }
+            };
+            let entry = &mut book.entries[index];
+
+            if let Some(v) = cell(Column::Keys) {
+                entry.keys = split_keys(v);
+            }
+            if let Some(v) = cell(Column::SecondaryKeys) {
+                entry.secondary_keys = split_keys(v);
+            }
+            if let Some(v) = cell(Column::Comment) {
+                entry.comment = v.to_string();
+            }
+            if let Some(v) = cell(Column::Content) {
+                entry.content = v.to_string();
+            }
+            if let Some(v) = cell(Column::Enabled).and_then(parse_bool) {
+                entry.enabled = v;
+            }
+            if let Some(v) = cell(Column::Constant).and_then(parse_bool) {
+                entry.constant = v;
+            }
+            if let Some(v) = cell(Column::Selective).and_then(parse_bool) {
+                entry.selective = v;
+            }
+            if let Some(v) = cell(Column::Order).and_then(parse_int) {
+                entry.insertion_order = v;
+            }
+            // 空的位置单元格不覆盖，新条目保留 before_char
+            if let Some(v) = cell(Column::Position)
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+            {
+                entry.position = v.to_string();
+            }
+        }
+        Ok(summary)
+    }
+
+    /// 关键词用逗号分隔；有关键词本身含逗号或以 `[` 开头时写成 JSON 数组，
+    /// 保证导入后原样还原
+    fn join_keys(keys: &[String]) -> String {
+        if keys
+            .iter()
+            .any(|k| k.contains([',', '，']) || k.starts_with('['))
+        {
+            serde_json::to_string(keys).unwrap_or_default()
+        } else {
+            keys.join(", ")
+        }
+    }
+
+    /// 关键词可以是 JSON 数组，或用中英文逗号分隔；`/…/标志` 形式的正则内部的逗号
+    /// (如 `/a{1,3}/`) 不作分隔
+    fn split_keys(cell: &str) -> Vec<String> {
+        let cell = cell.trim();
+        if cell.starts_with('[')
+            && let Ok(keys) = serde_json::from_str::<Vec<String>>(cell)
+        {
+            return keys;
+        }
+
+        let mut keys = Vec::new();
+        let mut rest = cell;
+        loop {
+            rest = rest.trim_start();
+            let end = regex_literal_len(rest)
+                .and_then(|len| {
+                    let after = rest[len..].trim_start();
+                    (after.is_empty() || after.starts_with([',', '，']))
+                        .then(|| rest.len() - after.len())
+                })
+                .or_else(|| rest.find([',', '，']))
+                .unwrap_or(rest.len());
+            let key = rest[..end].trim();
+            if !key.is_empty() {
+                keys.push(key.to_string());
+            }
+            match rest[end..].chars().next() {
+                Some(sep) => rest = &rest[end + sep.len_utf8()..],
+                None => return keys,
+            }
+        }
+    }
+
+    /// 以 `/` 开头的正则字面量 (含标志) 的字节长度；没有结尾斜杠时为 None
+    fn regex_literal_len(s: &str) -> Option<usize> {
+        let body = s.strip_prefix('/')?;
+        let mut escaped = false;
+        let mut in_class = false;
+        for (i, c) in body.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '[' => in_class = true,
+                ']' => in_class = false,
+                '/' if !in_class && i > 0 => {
+                    let flags = body[i + 1..]
+                        .bytes()
+                        .take_while(u8::is_ascii_alphabetic)
+                        .count();
+                    return Some(i + 2 + flags);
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// XLSX 中的数字读出来可能是 “3.0”
+    fn parse_int(cell: &str) -> Option<i32> {
+        let cell = cell.trim();
+        cell.parse::<i32>()
+            .ok()
+            .or_else(|| cell.parse::<f64>().ok().map(|f| f as i32))
+    }
+
+    fn parse_bool(cell: &str) -> Option<bool> {
+        match cell.trim().to_lowercase().as_str() {
+            "是" | "true" | "yes" | "y" | "1" | "√" | "✓" => Some(true),
+            "否" | "false" | "no" | "n" | "0" | "×" => Some(false),
+            _ => None,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::fixtures::{book, entry};
+
+        fn rows(table: &[&[&str]]) -> Vec<Vec<String>> {
+            table
+                .iter()
+                .map(|row| row.iter().map(|c| c.to_string()).collect())
+                .collect()
+        }
+
+        #[test]
+        fn keys_survive_a_round_trip() {
+            let mut original = entry(0, &["/a{1,3}/i", "猫，狗", "[x]"], "内容");
+            original.secondary_keys = vec!["黑".to_string(), "白".to_string()];
+            let exported = book(vec![original, entry(1, &["/\\d+,\\d+/", "普通"], "")]);
+
+            for bytes in [
+                export_csv(&exported).unwrap(),
+                export_xlsx(&exported).unwrap(),
+            ] {
+                let mut imported = book(vec![entry(0, &[], ""), entry(1, &[], "")]);
+                let summary = merge(&mut imported, &read(&bytes).unwrap()).unwrap();
+                assert_eq!((summary.updated, summary.added), (2, 0));
+                for (a, b) in exported.entries.iter().zip(&imported.entries) {
+                    assert_eq!(a.keys, b.keys);
+                    assert_eq!(a.secondary_keys, b.secondary_keys);
+                    assert_eq!(a.content, b.content);
+                }
+            }
+        }
+
+        #[test]
+        fn oversized_cells_are_rejected_before_writing_xlsx() {
+            let mut long = entry(3, &["长"], &"字".repeat(XLSX_CELL_LIMIT + 1));
+            long.comment = "长条目".to_string();
+            let book = book(vec![long]);
+            let err = export_xlsx(&book).unwrap_err().to_string();
+            assert!(err.contains("长条目") && err.contains("内容"), "{}", err);
+            // CSV 没有这个限制
+            let table = read(&export_csv(&book).unwrap()).unwrap();
+            assert_eq!(table[1][4].chars().count(), XLSX_CELL_LIMIT + 1);
+        }
+
+        #[test]
+        fn split_keys_keeps_regex_commas() {
+            assert_eq!(split_keys("/a{1,3}/g, b，c"), ["/a{1,3}/g", "b", "c"]);
+            assert_eq!(split_keys(r"/[,/]/, d"), [r"/[,/]/", "d"]);
+            // 斜杠后不是分隔符时按普通关键词处理
+            assert_eq!(split_keys("/usr/bin, e"), ["/usr/bin", "e"]);
+            assert_eq!(split_keys("/a, b"), ["/a", "b"]);
+            assert_eq!(split_keys(r#"["x, y", "z"]"#), ["x, y", "z"]);
+            assert!(split_keys(" , ").is_empty());
+        }
+
+        #[test]
+        fn merge_updates_by_id_and_appends_the_rest() {
+            let mut kept = entry(5, &["旧"], "旧内容");
+            kept.comment = "备注".to_string();
+            let mut book = book(vec![kept, entry(9, &["不动"], "")]);
+            let table = rows(&[
+                &["ID", "Keys", "内容", "颜色"],
+                &["5", "新", "新内容", "红"],
+                &["", "甲", "追加", ""],
+                &["42", "乙", "指定 id", ""],
+                &["", "", "", ""],
+            ]);
+            let summary = merge(&mut book, &table).unwrap();
+            assert_eq!((summary.updated, summary.added), (1, 2));
+
+            let ids: Vec<i32> = book.entries.iter().filter_map(|e| e.id).collect();
+            assert_eq!(ids, [5, 9, 10, 42]);
+            assert_eq!(book.entries[0].keys, ["新"]);
+            assert_eq!(book.entries[0].content, "新内容");
+            assert_eq!(book.entries[0].comment, "备注");
+            assert_eq!(book.entries[1].keys, ["不动"]);
+            assert!(book.entries[2].enabled);
+            assert_eq!(book.entries[2].position, "before_char");
+            assert_eq!(book.entries[3].content, "指定 id");
+
+            let table = rows(&[&["关键词", "位置"], &["丙", "after_char"], &["丁", ""]]);
+            merge(&mut book, &table).unwrap();
+            assert_eq!(book.entries[4].position, "after_char");
+            assert_eq!(book.entries[5].position, "before_char");
+        }
+
+        #[test]
+        fn appended_ids_do_not_overflow() {
+            let mut book = book(vec![entry(i32::MAX, &["a"], ""), entry(0, &["b"], "")]);
+            merge(&mut book, &rows(&[&["关键词"], &["c"]])).unwrap();
+            assert_eq!(book.entries[2].id, Some(1));
+        }
+
+        #[test]
+        fn header_is_required() {
+            let mut book = CharacterBook::default();
+            assert!(merge(&mut book, &rows(&[&["颜色", "大小"], &["红", "大"]])).is_err());
+            assert!(merge(&mut book, &[]).is_err());
+            assert!(book.entries.is_empty());
+        }
+
+        #[test]
+        fn csv_reader_strips_bom_and_rejects_other_encodings() {
+            let table = read("\u{feff}关键词,内容\n\"a, b\",\"多\n行\"\n".as_bytes()).unwrap();
+            assert_eq!(table, rows(&[&["关键词", "内容"], &["a, b", "多\n行"]]));
+            assert!(read(&[0xB9, 0xD8, 0xBC, 0xFC]).is_err());
+        }
+    }
+}
+
+mod error {
+    //! 角色卡解析错误。
+    //!
+    //! 每个变体对应一个失败环节，并带上定位所需的上下文 (数据块名称、字节偏移、
+    //! JSON 字段路径与行列号)，调用方可据此给出不同的提示，而不只是转述一句话。
+
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    pub enum CardError {
+        #[error("无法识别的文件格式 (支持 PNG / WebP / JPEG / CharX / JSON)")]
+        UnknownFormat,
+
+        #[error("不是有效的 PNG 图片")]
+        NotPng,
+
+        #[error("PNG 缺少 IEND 块，文件可能不完整")]
+        MissingIend,
+
+        #[error("PNG 在偏移 {offset} 处被截断 (数据块头只剩 {available} 字节)")]
+        TruncatedHeader { offset: usize, available: usize },
+
+        #[error(
+            "PNG 在偏移 {offset} 处被截断 (数据块 {chunk} 声明长度 {declared} 字节，只剩 {available} 字节)"
+        )]
+        TruncatedChunk {
+            chunk: String,
+            offset: usize,
+            declared: usize,
+            available: usize,
+        },
+
+        #[error("PNG 在偏移 {offset} 处出现无效的数据块类型 {kind:02X?}，文件可能已损坏")]
+        InvalidChunkType { offset: usize, kind: [u8; 4] },
+
+        #[error("PNG 数据块 {chunk} (偏移 {offset}) 声明长度 {length} 字节，超过上限 {limit} 字节")]
+        ChunkTooLarge {
+            chunk: String,
+            offset: usize,
+            length: usize,
+            limit: usize,
+        },
+
+        #[error("PNG 数据总长度超过上限 {limit} 字节")]
+        TotalTooLarge { limit: usize },
+
+        #[error("PNG 数据块 {chunk} (偏移 {offset}) 的 CRC 校验失败，文件可能已损坏")]
+        CrcMismatch { chunk: String, offset: usize },
+
+        /// WebP 等容器中声明的长度超出文件末尾
+        #[error("{container} 数据块 {chunk} (偏移 {offset}) 长度越界，文件可能已损坏")]
+        Malformed {
+            container: &'static str,
+            chunk: String,
+            offset: usize,
+        },
+
+        /// 容器本身完好，但没有携带角色卡数据
+        #[error("未在 {container} 图片中找到角色卡信息 ({sources})")]
+        NotFound {
+            container: &'static str,
+            sources: &'static str,
+        },
+
+        #[error("{chunk} 数据的 Base64 解码失败: {source}")]
+        Base64 {
+            chunk: &'static str,
+            source: base64::DecodeError,
+        },
+
+        #[error("{chunk} 不是有效的 UTF-8 文本")]
+        Utf8 { chunk: &'static str },
+
+        /// JSON 语法错误带行列号；字段类型不符时带字段路径 (如 `data.character_book.entries[3].id`)
+        #[error("{label} JSON 解析失败{}: {message}", json_location(.path, *.line, *.column))]
+        Json {
+            label: &'static str,
+            path: Option<String>,
+            line: usize,
+            column: usize,
+            message: String,
+        },
+
+        #[error("{label} 数据中没有角色名称、描述或开场白，无法识别为角色卡")]
+        EmptyCard { label: &'static str },
+
+        #[error("JSON 顶层不是对象，无法识别为角色卡")]
+        NotAnObject,
+
+        #[error("不是有效的 CharX 文件: {0}")]
+        Archive(#[from] zip::result::ZipError),
+
+        #[error("CharX 中缺少 card.json")]
+        MissingCardJson,
+
+        #[error("CharX 中的 {name} 解压后超过上限 {limit} 字节")]
+        EntryTooLarge { name: String, limit: usize },
+
+        /// 打包时头像图片本身的问题
+        #[error("头像{0}")]
+        Avatar(Box<CardError>),
+
+        #[error("角色卡 JSON 生成失败: {0}")]
+        Serialize(#[source] kovi::serde_json::Error),
+    }
+
+    impl CardError {
+        /// 由 serde_json 的错误构造，只有行列号 (解析为 `Value` 时没有字段路径)
+        pub fn json(label: &'static str, err: kovi::serde_json::Error) -> Self {
+            Self::json_at(label, None, err)
+        }
+
+        /// 由 serde_path_to_error 的错误构造，记录出错字段的路径
+        pub fn json_path(
+            label: &'static str,
+            err: serde_path_to_error::Error<kovi::serde_json::Error>,
+        ) -> Self {
+            let path = err
+                .path()
+                .iter()
+                .next()
+                .is_some()
+                .then(|| err.path().to_string());
+            Self::json_at(label, path, err.into_inner())
+        }
+
+        fn json_at(
+            label: &'static str,
+            path: Option<String>,
+            err: kovi::serde_json::Error,
+        ) -> Self {
+            let (line, column) = (err.line(), err.column());
+            // serde_json 的 Display 末尾自带位置，统一由 json_location 输出
+            let message = err.to_string();
+            let message = message
+                .strip_suffix(&format!(" at line {} column {}", line, column))
+                .unwrap_or(&message)
+                .to_string();
+            Self::Json {
+                label,
+                path,
+                line,
+                column,
+                message,
+            }
+        }
+    }
+
+    fn json_location(path: &Option<String>, line: usize, column: usize) -> String {
+        match path {
+            Some(p) => format!(" (字段 {})", p),
+            // 从 Value 反序列化时行号为 0
+            None if line > 0 => format!(" (第 {} 行第 {} 列)", line, column),
+            None => String::new(),
+        }
+    }
+}
+
+mod parser {
+    use super::divergence::{self, Difference};
+    use super::error::CardError;
+    use super::types::lenient;
+    use super::types::{
+        CharaCardV3, CharacterBook, CharacterData, LoreEntry, RegexScript, WorldInfo,
+    };
+    use super::{jsregex, worldinfo};
+    use base64::{Engine as _, engine::general_purpose};
+    use flate2::Crc;
+    use flate2::read::ZlibDecoder;
+    use kovi::serde_json;
+    use std::io::{Cursor, Read};
+
+    type Result<T, E = CardError> = std::result::Result<T, E>;
+
+    const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+    /// 统一的解析结果，无论输入是 PNG、CharX 还是 JSON
+    pub struct ParsedCard {
+        pub kind: CardKind,
+        /// 实际读到的规范，以及卡内声明的 spec_version (V1 为空)
+        pub spec: CardSpec,
+        pub spec_version: String,
+        /// 是否使用了 TavernAI V1 旧字段名 (char_name 等) 并已转换
+        pub upgraded_from_v1: bool,
+        /// 独立世界书时，name 为世界书名称，character_book 为世界书本体
+        pub data: CharacterData,
+        /// 导出用的完整 JSON 字符串
+        pub json: String,
+        /// CharX 包内附带的资源文件 (PNG 为空)
+        pub assets: Vec<EmbeddedAsset>,
+        /// 查找正则无法编译或在酒馆中有隐患的正则脚本
+        pub regex_issues: Vec<jsregex::ScriptIssue>,
+        /// 图片中实际读取的数据块，如 `chara (tEXt)`；JSON 与 CharX 为空
+        pub source: String,
+        /// 读取前已尝试但失败的数据块及原因
+        pub skipped_payloads: Vec<String>,
+        /// 同时带有 ccv3 与 chara 且内容不一致时的差异
+        pub divergence: Vec<Difference>,
+        /// 类型不符、已自动转换的字段值说明
+        pub coercions: Vec<String>,
+    }
+
+    /// 输入文件的内容类型
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CardKind {
+        Character,
+        Lorebook,
+    }
+
+    /// 角色卡规范版本
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CardSpec {
+        /// TavernAI 扁平格式，字段位于顶层
+        V1,
+        /// chara_card_v2，字段位于 data 中
+        V2,
+        /// chara_card_v3
+        V3,
+        /// 独立世界书，不属于任何角色卡规范
+        Lorebook,
+    }
+
+    impl std::fmt::Display for CardSpec {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                CardSpec::V1 => write!(f, "V1"),
+                CardSpec::V2 => write!(f, "V2"),
+                CardSpec::V3 => write!(f, "V3"),
+                CardSpec::Lorebook => write!(f, "世界书"),
+            }
+        }
+    }
+
+    /// CharX 包内的资源文件
+    pub struct EmbeddedAsset {
+        pub path: String,
+        pub size: u64,
+    }
+
+    /// 根据文件头识别出的容器格式
+    enum Container {
+        Png,
+        WebP,
+        Jpeg,
+        CharX,
+        Json,
+    }
+
+    fn sniff_container(bytes: &[u8]) -> Option<Container> {
+        if bytes.starts_with(&PNG_SIGNATURE) {
+            return Some(Container::Png);
+        }
+        if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            return Some(Container::WebP);
+        }
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            return Some(Container::Jpeg);
+        }
+        if bytes.starts_with(b"PK\x03\x04") {
+            return Some(Container::CharX);
+        }
+        let text_start = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+        if text_start.trim_ascii_start().starts_with(b"{") {
+            return Some(Container::Json);
+        }
+        None
+    }
+
+    /// 根据文件头自动识别格式并解析
+    pub fn parse_card(bytes: &[u8], limits: &PngLimits) -> Result<ParsedCard> {
+        match sniff_container(bytes) {
+            Some(Container::Png) => parse_png(bytes, limits),
+            Some(Container::WebP) => parse_webp(bytes),
+            Some(Container::Jpeg) => parse_jpeg(bytes),
+            Some(Container::CharX) => parse_charx(bytes, limits),
+            Some(Container::Json) => {
+                let text_start = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+                parse_json(text_start)
+            }
+            None => Err(CardError::UnknownFormat),
+        }
+    }
+
+    /// 从 PNG 字节中解析角色卡数据
+    pub fn parse_png(bytes: &[u8], limits: &PngLimits) -> Result<ParsedCard> {
+        let mut payloads = CardPayloads::default();
+
+        for chunk in png_chunks(bytes, limits)? {
+            let chunk = chunk?;
+            if let Ok(kind @ ("tEXt" | "zTXt" | "iTXt")) = std::str::from_utf8(&chunk.kind)
+                && let Some((keyword, text)) =
+                    read_text_chunk(kind, chunk.data, limits.max_chunk_size)
+            {
+                payloads.insert(&keyword, format!("{} ({})", keyword, kind), text);
+            }
+        }
+
+        payloads.into_card(CardError::NotFound {
+            container: "PNG",
+            sources: "chara/ccv3",
+        })
+    }
+
+    /// 读取 PNG 时的长度上限，防止恶意图片让机器人分配大量内存
+    #[derive(Debug, Clone, Copy)]
+    pub struct PngLimits {
+        /// 单个数据块的最大长度，也是压缩文本解压后的最大长度
+        pub max_chunk_size: usize,
+        /// 所有数据块长度之和的上限
+        pub max_total_size: usize,
+        /// 是否校验每个数据块的 CRC
+        pub verify_crc: bool,
+    }
+
+    impl Default for PngLimits {
+        fn default() -> Self {
+            Self {
+                max_chunk_size: 32 * 1024 * 1024,
+                max_total_size: 128 * 1024 * 1024,
+                verify_crc: false,
+            }
+        }
+    }
+
+    /// 一个 PNG 数据块，`raw` 为包含长度与 CRC 的完整字节
+    struct PngChunk<'a> {
+        kind: [u8; 4],
+        data: &'a [u8],
+        raw: &'a [u8],
+    }
+
+    /// 逐块读取 PNG，只借用原始字节，不按声明的长度分配内存；
+    /// 读到 IEND 即停止，长度越界、截断或缺少 IEND 时返回错误
+    struct PngChunks<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+        total: usize,
+        limits: PngLimits,
+        done: bool,
+    }
+
+    fn png_chunks<'a>(bytes: &'a [u8], limits: &PngLimits) -> Result<PngChunks<'a>> {
+        if !is_png(bytes) {
+            return Err(CardError::NotPng);
+        }
+        Ok(PngChunks {
+            bytes,
+            pos: PNG_SIGNATURE.len(),
+            total: 0,
+            limits: *limits,
+            done: false,
+        })
+    }
+
+    impl<'a> PngChunks<'a> {
+        fn read_chunk(&mut self) -> Result<PngChunk<'a>> {
+            let pos = self.pos;
+            let remaining = self.bytes.len() - pos;
+            if remaining == 0 {
+                return Err(CardError::MissingIend);
+            }
+            if remaining < 12 {
+                return Err(CardError::TruncatedHeader {
+                    offset: pos,
+                    available: remaining,
+                });
+            }
+
+            let length = u32::from_be_bytes(take4(&self.bytes[pos..])) as usize;
+            let kind: [u8; 4] = take4(&self.bytes[pos + 4..]);
+            let name = String::from_utf8_lossy(&kind).into_owned();
+            if !kind.iter().all(u8::is_ascii_alphabetic) {
+                return Err(CardError::InvalidChunkType { offset: pos, kind });
+            }
+            if length > self.limits.max_chunk_size {
+                return Err(CardError::ChunkTooLarge {
+                    chunk: name,
+                    offset: pos,
+                    length,
+                    limit: self.limits.max_chunk_size,
+                });
+            }
+            self.total += length;
+            if self.total > self.limits.max_total_size {
+                return Err(CardError::TotalTooLarge {
+                    limit: self.limits.max_total_size,
+                });
+            }
+            if length > remaining - 12 {
+                return Err(CardError::TruncatedChunk {
+                    chunk: name,
+                    offset: pos,
+                    declared: length,
+                    available: remaining - 12,
+                });
+            }
+
+            let end = pos + 12 + length;
+            let data = &self.bytes[pos + 8..pos + 8 + length];
+            if self.limits.verify_crc {
+                let mut crc = Crc::new();
+                crc.update(&self.bytes[pos + 4..pos + 8 + length]);
+                let stored = u32::from_be_bytes(take4(&self.bytes[end - 4..]));
+                if crc.sum() != stored {
+                    return Err(CardError::CrcMismatch {
+                        chunk: name,
+                        offset: pos,
+                    });
+                }
+            }
+
+            self.pos = end;
+            Ok(PngChunk {
+                kind,
+                data,
+                raw: &self.bytes[pos..end],
+            })
+        }
+    }
+
+    impl<'a> Iterator for PngChunks<'a> {
+        type Item = Result<PngChunk<'a>>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.done {
+                return None;
+            }
+            let chunk = self.read_chunk();
+            // 出错后不再继续；IEND 之后的数据一律忽略
+            self.done = chunk.as_ref().map_or(true, |c| &c.kind == b"IEND");
+            Some(chunk)
+        }
+    }
+
+    /// 取前 4 个字节；调用前已确认长度足够
+    fn take4(bytes: &[u8]) -> [u8; 4] {
+        [bytes[0], bytes[1], bytes[2], bytes[3]]
+    }
+
+    pub fn is_png(bytes: &[u8]) -> bool {
+        bytes.starts_with(&PNG_SIGNATURE)
+    }
+
+    /// 把角色卡写入 PNG 头像，返回新的 PNG 字节
+    ///
+    /// 同时写入 V3 的 `ccv3` 与兼容 V2 的 `chara` 两个 tEXt 块 (插入在 IEND 之前)，
+    /// 头像中原有的角色卡数据块会被移除。头像按 `limits` 读取，与解析时一致。
+    pub fn write_card_png(
+        avatar: &[u8],
+        card: &CharacterData,
+        limits: &PngLimits,
+    ) -> Result<Vec<u8>> {
+        if !is_png(avatar) {
+            return Err(CardError::Avatar(Box::new(CardError::NotPng)));
+        }
+
+        let ccv3 = ccv3_json(card);
+        let chara = v2_compatible_json(card)?;
+        let ccv3_b64 = general_purpose::STANDARD
+            .encode(serde_json::to_string(&ccv3).map_err(CardError::Serialize)?);
+        let chara_b64 = general_purpose::STANDARD
+            .encode(serde_json::to_string(&chara).map_err(CardError::Serialize)?);
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        for chunk in png_chunks(avatar, limits)? {
+            let chunk = chunk.map_err(|e| CardError::Avatar(Box::new(e)))?;
+
+            if &chunk.kind == b"IEND" {
+                write_chunk(&mut out, b"tEXt", &text_chunk_data("chara", &chara_b64));
+                write_chunk(&mut out, b"tEXt", &text_chunk_data("ccv3", &ccv3_b64));
+                out.extend_from_slice(chunk.raw);
+                break;
+            }
+
+            let is_card_chunk = matches!(&chunk.kind, b"tEXt" | b"zTXt" | b"iTXt")
+                && chunk
+                    .data
+                    .iter()
+                    .position(|&b| b == 0)
+                    .and_then(|n| std::str::from_utf8(&chunk.data[..n]).ok())
+                    .is_some_and(|k| {
+                        k.eq_ignore_ascii_case("chara") || k.eq_ignore_ascii_case("ccv3")
+                    });
+            if !is_card_chunk {
+                out.extend_from_slice(chunk.raw);
+            }
+        }
+        Ok(out)
+    }
+
+    /// V3 包装格式的完整角色卡 JSON
+    pub fn ccv3_json(card: &CharacterData) -> serde_json::Value {
+        serde_json::json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": card,
+        })
+    }
+
+    /// V2 规范的 chara 数据：spec/data 包装，并在顶层保留 V1 字段供旧前端读取
+    fn v2_compatible_json(card: &CharacterData) -> Result<serde_json::Value> {
+        // V2 规范中不存在的字段
+        const V3_ONLY_FIELDS: &[&str] = &[
+            "group_only_greetings",
+            "assets",
+            "nickname",
+            "creator_notes_multilingual",
+            "source",
+            "creation_date",
+            "modification_date",
+        ];
+
+        let mut data = serde_json::to_value(card).map_err(CardError::Serialize)?;
+        if let Some(obj) = data.as_object_mut() {
+            for key in V3_ONLY_FIELDS {
+                obj.remove(*key);
+            }
+        }
+        Ok(serde_json::json!({
+            "name": card.name,
+            "description": card.description,
+            "personality": card.personality,
+            "scenario": card.scenario,
+            "first_mes": card.first_mes,
+            "mes_example": card.mes_example,
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": data,
+        }))
+    }
+
+    fn text_chunk_data(keyword: &str, text: &str) -> Vec<u8> {
+        let mut data = Vec::with_capacity(keyword.len() + 1 + text.len());
+        data.extend_from_slice(keyword.as_bytes());
+        data.push(0);
+        data.extend_from_slice(text.as_bytes());
+        data
+    }
+
+    /// 写入一个完整的 PNG 数据块 (长度 + 类型 + 数据 + CRC)
+    fn write_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+        let mut crc = Crc::new();
+        crc.update(chunk_type);
+        crc.update(data);
+
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(chunk_type);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&crc.sum().to_be_bytes());
+    }
+
+    /// 从 WebP 字节中解析角色卡数据 (EXIF UserComment 或 XMP)
+    pub fn parse_webp(bytes: &[u8]) -> Result<ParsedCard> {
+        let mut payloads = CardPayloads::default();
+
+        // RIFF 头 12 字节之后是 [FourCC][小端长度][数据][奇数长度补齐]
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let fourcc = &bytes[pos..pos + 4];
+            let length = u32::from_le_bytes(take4(&bytes[pos + 4..])) as usize;
+            let start = pos + 8;
+            let end = start
+                .checked_add(length)
+                .filter(|&e| e <= bytes.len())
+                .ok_or_else(|| CardError::Malformed {
+                    container: "WebP",
+                    chunk: String::from_utf8_lossy(fourcc).into_owned(),
+                    offset: pos,
+                })?;
+            let data = &bytes[start..end];
+
+            match fourcc {
+                b"EXIF" => {
+                    // 部分编码器会保留 JPEG 风格的 "Exif\0\0" 前缀
+                    let tiff = data.strip_prefix(b"Exif\0\0").unwrap_or(data);
+                    if let Some(text) = read_exif_user_comment(tiff) {
+                        payloads.insert_exif(text);
+                    }
+                }
+                b"XMP " => payloads.insert_xmp(&String::from_utf8_lossy(data)),
+                _ => {}
+            }
+            pos = end + (length & 1);
+        }
+
+        payloads.into_card(CardError::NotFound {
+            container: "WebP",
+            sources: "EXIF/XMP",
+        })
+    }
+
+    /// 从 JPEG 字节中解析角色卡数据 (APP1 段中的 EXIF UserComment 或 XMP)
+    pub fn parse_jpeg(bytes: &[u8]) -> Result<ParsedCard> {
+        const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+        let mut payloads = CardPayloads::default();
+
+        // SOI 之后是 [0xFF][标记][大端长度 (含自身 2 字节)][数据]
+        let mut pos = 2;
+        while pos + 4 <= bytes.len() {
+            if bytes[pos] != 0xFF {
+                break;
+            }
+            let marker = bytes[pos + 1];
+            // 填充字节
+            if marker == 0xFF {
+                pos += 1;
+                continue;
+            }
+            // SOS 之后是图像数据，元数据段不会再出现
+            if marker == 0xDA || marker == 0xD9 {
+                break;
+            }
+            let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+            let end = (pos + 2 + length).min(bytes.len());
+            let data = &bytes[(pos + 4).min(end)..end];
+
+            if marker == 0xE1 {
+                if let Some(tiff) = data.strip_prefix(b"Exif\0\0") {
+                    if let Some(text) = read_exif_user_comment(tiff) {
+                        payloads.insert_exif(text);
+                    }
+                } else if let Some(xmp) = data.strip_prefix(XMP_HEADER) {
+                    payloads.insert_xmp(&String::from_utf8_lossy(xmp));
+                }
+            }
+            pos = end;
+        }
+
+        payloads.into_card(CardError::NotFound {
+            container: "JPEG",
+            sources: "EXIF/XMP",
+        })
+    }
+
+    /// 从各种容器中收集到的角色卡原始文本 (Base64 或 JSON)
+    #[derive(Default)]
+    struct CardPayloads {
+        found: Vec<Payload>,
+        /// 关键字可识别、但内容无法取出的数据块 (如解压失败)，格式为 "来源: 原因"
+        unreadable: Vec<String>,
+    }
+
+    struct Payload {
+        /// 读取顺序：ccv3 为 0，chara 为 1，其余来源为 2
+        rank: u8,
+        /// 错误信息中使用的名称
+        label: &'static str,
+        /// 数据所在位置，如 `ccv3 (zTXt)`、`XMP chara`
+        source: String,
+        text: String,
+    }
+
+    impl Payload {
+        fn parse(&self) -> Result<ParsedCard> {
+            let json = decode_payload(&self.text, self.label)?;
+            character_from_json(&json, self.label)
+        }
+    }
+
+    impl CardPayloads {
+        /// 按关键字归类，忽略与角色卡无关的文本；同一关键字出现多次时全部保留
+        fn insert(&mut self, keyword: &str, source: String, text: Result<String, String>) {
+            let (rank, label) = match keyword.to_lowercase().as_str() {
+                "ccv3" => (0, "ccv3"),
+                "chara" => (1, "chara"),
+                _ => return,
+            };
+            match text {
+                Ok(text) => self.found.push(Payload {
+                    rank,
+                    label,
+                    source,
+                    text,
+                }),
+                Err(reason) => self.unreadable.push(format!("{}: {}", source, reason)),
+            }
+        }
+
+        /// EXIF UserComment 没有关键字，排在 ccv3 / chara 之后尝试
+        fn insert_exif(&mut self, text: String) {
+            self.found.push(Payload {
+                rank: 2,
+                label: "EXIF",
+                source: "EXIF UserComment".to_string(),
+                text,
+            });
+        }
+
+        /// XMP 中的 ccv3 / chara 可能是属性 (key="...") 或元素 (<ns:key>...</ns:key>)
+        fn insert_xmp(&mut self, xmp: &str) {
+            for key in ["ccv3", "chara"] {
+                if let Some(text) = find_xmp_value(xmp, key) {
+                    self.insert(key, format!("XMP {}", key), Ok(text));
+                }
+            }
+        }
+
+        /// 依次尝试 ccv3、chara 与其余来源，返回第一个能读出角色卡的；
+        /// 前面失败的来源连同原因记入 `skipped_payloads`。
+        /// 全部失败时返回优先级最高的那个错误。
+        fn into_card(self, not_found: CardError) -> Result<ParsedCard> {
+            let CardPayloads {
+                mut found,
+                mut unreadable,
+            } = self;
+            found.sort_by_key(|p| p.rank);
+
+            let mut first_error = None;
+            let mut rest = found.iter();
+            for payload in rest.by_ref() {
+                match payload.parse() {
+                    Ok(mut card) => {
+                        card.source = payload.source.clone();
+                        card.skipped_payloads = unreadable;
+                        // 读的是 ccv3 时，再与第一份可读的 chara 对比
+                        if payload.label == "ccv3"
+                            && let Some(chara) = rest
+                                .filter(|p| p.label == "chara")
+                                .find_map(|p| p.parse().ok())
+                        {
+                            card.divergence = divergence::compare(&card.data, &chara.data);
+                        }
+                        return Ok(card);
+                    }
+                    Err(e) => {
+                        unreadable.push(format!("{}: {}", payload.source, e));
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+            Err(first_error.unwrap_or(not_found))
+        }
+    }
+
+    /// 反序列化为具体类型，同时返回宽松读取时转换的字段说明；失败时记录出错字段的路径
+    fn from_value<T: serde::de::DeserializeOwned>(
+        value: serde_json::Value,
+        label: &'static str,
+    ) -> Result<(T, Vec<String>)> {
+        let (result, coercions) = lenient::collect(|| serde_path_to_error::deserialize(value));
+        result
+            .map(|t| (t, coercions))
+            .map_err(|e| CardError::json_path(label, e))
+    }
+
+    /// 识别角色卡 JSON 的形态 (V1 扁平 / V2 包装 / V3 包装)，统一转换为 CharacterData
+    fn character_from_json(json_str: &str, label: &'static str) -> Result<ParsedCard> {
+        let value: serde_json::Value =
+            serde_json::from_str(json_str).map_err(|e| CardError::json(label, e))?;
+        character_from_value(value, label)
+    }
+
+    fn character_from_value(
+        mut value: serde_json::Value,
+        label: &'static str,
+    ) -> Result<ParsedCard> {
+        let is_wrapped = value.get("data").is_some_and(|d| d.is_object());
+
+        let fields = if is_wrapped {
+            value.get_mut("data")
+        } else {
+            Some(&mut value)
+        };
+        let upgraded = fields
+            .and_then(|v| v.as_object_mut())
+            .map(upgrade_v1_fields)
+            .unwrap_or_default();
+
+        let (data, json, spec, spec_version, coercions) = if is_wrapped {
+            // V2 / V3: { spec, spec_version, data: {...} }
+            let (wrapper, coercions): (CharaCardV3, _) = from_value(value, label)?;
+            let spec = match wrapper.spec.as_str() {
+                "chara_card_v3" => CardSpec::V3,
+                "chara_card_v2" => CardSpec::V2,
+                // spec 缺失或写错时，按版本号推断
+                _ if wrapper.spec_version.starts_with('3') => CardSpec::V3,
+                _ => CardSpec::V2,
+            };
+            let json = serde_json::to_string_pretty(&wrapper).map_err(CardError::Serialize)?;
+            (wrapper.data, json, spec, wrapper.spec_version, coercions)
+        } else {
+            // V1: 字段直接位于顶层
+            let (card, coercions): (CharacterData, _) = from_value(value, label)?;
+            let json = serde_json::to_string_pretty(&card).map_err(CardError::Serialize)?;
+            (card, json, CardSpec::V1, String::new(), coercions)
+        };
+
+        if data.name.trim().is_empty()
+            && data.description.trim().is_empty()
+            && data.first_mes.trim().is_empty()
+        {
+            return Err(CardError::EmptyCard { label });
+        }
+
+        let regex_issues = data
+            .extensions
+            .as_ref()
+            .map(|ext| jsregex::check_scripts(&ext.regex_scripts))
+            .unwrap_or_default();
+
+        Ok(ParsedCard {
+            kind: CardKind::Character,
+            spec,
+            spec_version,
+            upgraded_from_v1: upgraded,
+            data,
+            json,
+            assets: Vec::new(),
+            regex_issues,
+            source: String::new(),
+            skipped_payloads: Vec::new(),
+            divergence: Vec::new(),
+            coercions,
+        })
+    }
+
+    /// TavernAI / Pygmalion V1 旧字段名到当前字段名的映射
+    const V1_FIELD_MAP: &[(&str, &str)] = &[
+        ("char_name", "name"),
+        ("char_persona", "description"),
+        ("world_scenario", "scenario"),
+        ("char_greeting", "first_mes"),
+        ("example_dialogue", "mes_example"),
+    ];
+
+    /// 把 V1 旧字段改名为当前字段，新字段已有内容时不覆盖
+    /// 返回是否发生了升级
+    fn upgrade_v1_fields(obj: &mut serde_json::Map<String, serde_json::Value>) -> bool {
+        let mut upgraded = false;
+        for (old, new) in V1_FIELD_MAP {
+            let target_empty = obj
+                .get(*new)
+                .is_none_or(|v| v.is_null() || v.as_str().is_some_and(|s| s.is_empty()));
+            if target_empty
+                && obj.get(*old).is_some_and(|v| !v.is_null())
+                && let Some(v) = obj.remove(*old)
+            {
+                obj.insert(new.to_string(), v);
+                upgraded = true;
+            }
+        }
+        upgraded
+    }
+
+    /// 读取 TIFF 结构中 Exif IFD 的 UserComment (0x9286)
+    fn read_exif_user_comment(tiff: &[u8]) -> Option<String> {
+        let little = match tiff.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        let u16_at = |off: usize| -> Option<u16> {
+            let b: [u8; 2] = tiff.get(off..off + 2)?.try_into().ok()?;
+            Some(if little {
+                u16::from_le_bytes(b)
+            } else {
+                u16::from_be_bytes(b)
+            })
+        };
+        let u32_at = |off: usize| -> Option<u32> {
+            let b: [u8; 4] = tiff.get(off..off + 4)?.try_into().ok()?;
+            Some(if little {
+                u32::from_le_bytes(b)
+            } else {
+                u32::from_be_bytes(b)
+            })
+        };
+        // 在 IFD 中查找标签，返回 (数据偏移, 数据长度)
+        let find_tag = |ifd: usize, tag: u16| -> Option<(usize, usize)> {
+            let count = u16_at(ifd)? as usize;
+            (0..count).find_map(|i| {
+                let entry = ifd + 2 + i * 12;
+                if u16_at(entry)? != tag {
+                    return None;
+                }
+                let len = u32_at(entry + 4)? as usize;
+                let offset = if len <= 4 {
+                    entry + 8
+                } else {
+                    u32_at(entry + 8)? as usize
+                };
+                Some((offset, len))
+            })
+        };
+
+        let ifd0 = u32_at(4)? as usize;
+        let (exif_ptr, _) = find_tag(ifd0, 0x8769)?;
+        let exif_ifd = u32_at(exif_ptr)? as usize;
+        let (offset, len) = find_tag(exif_ifd, 0x9286)?;
+        let raw = tiff.get(offset..offset.checked_add(len)?)?;
+
+        // 前 8 字节是字符集标识
+        let (charset, body) = (raw.get(..8)?, raw.get(8..)?);
+        let text = if charset == b"UNICODE\0" {
+            let units: Vec<u16> = body
+                .chunks_exact(2)
+                .map(|c| {
+                    if little {
+                        u16::from_le_bytes([c[0], c[1]])
+                    } else {
+                        u16::from_be_bytes([c[0], c[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        } else {
+            String::from_utf8_lossy(body).into_owned()
+        };
+        let text = text.trim_matches(char::from(0)).trim().to_string();
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// 在 XMP 文本中查找指定关键字的值
+    fn find_xmp_value(xmp: &str, key: &str) -> Option<String> {
+        // 属性形式: ns:key="..." 或 key="..."
+        // 命名空间声明 xmlns:key="http://..." 的形式相同，需要跳过
+        for prefix in [format!(":{}=\"", key), format!(" {}=\"", key)] {
+            let found = xmp
+                .match_indices(&prefix)
+                .find(|(start, _)| !xmp[..*start].ends_with("xmlns"));
+            if let Some((start, _)) = found {
+                let rest = &xmp[start + prefix.len()..];
+                let end = rest.find('"')?;
+                return Some(unescape_xml(&rest[..end]));
+            }
+        }
+        // 元素形式: <ns:key>...</ns:key>
+        let open = format!(":{}>", key);
+        let start = xmp.find(&open)? + open.len();
+        let rest = &xmp[start..];
+        let end = rest.find('<')?;
+        Some(unescape_xml(rest[..end].trim()))
+    }
+
+    fn unescape_xml(s: &str) -> String {
+        s.replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&")
+    }
+
+    /// 从 CharX (V3 规范的 ZIP 容器) 中解析角色卡数据
+    /// card.json 位于压缩包根目录，其余文件视为内嵌资源；
+    /// card.json 解压后的大小受 `max_total_size` 限制，防止压缩炸弹
+    pub fn parse_charx(bytes: &[u8], limits: &PngLimits) -> Result<ParsedCard> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+
+        let limit = limits.max_total_size;
+        let too_large = || CardError::EntryTooLarge {
+            name: "card.json".to_string(),
+            limit,
+        };
+        let entry = archive
+            .by_name("card.json")
+            .map_err(|_| CardError::MissingCardJson)?;
+        // 头部声明的大小可以伪造，读取时仍按上限截断
+        if entry.size() > limit as u64 {
+            return Err(too_large());
+        }
+        let mut raw = Vec::new();
+        entry
+            .take(limit as u64 + 1)
+            .read_to_end(&mut raw)
+            .map_err(zip::result::ZipError::Io)?;
+        if raw.len() > limit {
+            return Err(too_large());
+        }
+        let json_str =
+            String::from_utf8(raw).map_err(|_| CardError::Utf8 { chunk: "card.json" })?;
+
+        let mut card = character_from_json(&json_str, "card.json")?;
+
+        let mut assets = Vec::new();
+        for i in 0..archive.len() {
+            // 只读取目录信息，不解压内容
+            let file = archive.by_index_raw(i)?;
+            let path = file.name()?.to_string();
+            if file.is_dir() || path == "card.json" {
+                continue;
+            }
+            assets.push(EmbeddedAsset {
+                path,
+                size: file.size(),
+            });
+        }
+
+        card.assets = assets;
+        Ok(card)
+    }
+
+    /// 解析纯 JSON 文件：V1 扁平角色卡、V2/V3 包装角色卡或独立世界书
+    pub fn parse_json(bytes: &[u8]) -> Result<ParsedCard> {
+        let text = std::str::from_utf8(bytes).map_err(|_| CardError::Utf8 {
+            chunk: "JSON 文件",
+        })?;
+        let value: serde_json::Value =
+            serde_json::from_str(text).map_err(|e| CardError::json("文件", e))?;
+        let obj = value.as_object().ok_or(CardError::NotAnObject)?;
+
+        // 角色卡 (包装或扁平) 与独立世界书都是对象，先排除世界书
+        // 独立世界书: 有 entries 但没有任何角色字段
+        let is_lorebook = obj.contains_key("entries")
+            && !["first_mes", "char_greeting", "personality", "char_persona"]
+                .iter()
+                .any(|k| obj.contains_key(*k));
+        if is_lorebook {
+            // 酒馆世界书文件的 entries 是以 uid 为键的对象，内嵌世界书则是数组
+            let is_world_file = obj.get("entries").is_some_and(|e| e.is_object());
+            let (book, full_json, coercions) = if is_world_file {
+                let (world, coercions): (WorldInfo, _) = from_value(value, "世界书")?;
+                let json = serde_json::to_string_pretty(&world).map_err(CardError::Serialize)?;
+                (
+                    worldinfo::to_character_book(&world, "世界书"),
+                    json,
+                    coercions,
+                )
+            } else {
+                // 内嵌格式无法直接导入酒馆，导出时转换为世界书文件格式
+                let (book, coercions): (CharacterBook, _) = from_value(value, "世界书")?;
+                let json = serde_json::to_string_pretty(&worldinfo::from_character_book(&book))
+                    .map_err(CardError::Serialize)?;
+                (book, json, coercions)
+            };
+            let name = if book.name.trim().is_empty() {
+                "世界书".to_string()
+            } else {
+                book.name.clone()
+            };
+            return Ok(ParsedCard {
+                kind: CardKind::Lorebook,
+                spec: CardSpec::Lorebook,
+                spec_version: String::new(),
+                upgraded_from_v1: false,
+                data: CharacterData {
+                    name,
+                    character_book: Some(book),
+                    ..Default::default()
+                },
+                json: full_json,
+                assets: Vec::new(),
+                regex_issues: Vec::new(),
+                source: String::new(),
+                skipped_payloads: Vec::new(),
+                divergence: Vec::new(),
+                coercions,
+            });
+        }
+
+        character_from_value(value, "角色卡")
+    }
+
+    /// 解码文本类 Chunk (tEXt / zTXt / iTXt)，返回 (关键字, 文本)
+    /// 读不出关键字时返回 None；关键字之后的内容有误时，文本位置给出原因
+    fn read_text_chunk(
+        chunk_type: &str,
+        data: &[u8],
+        limit: usize,
+    ) -> Option<(String, Result<String, String>)> {
+        let null_pos = data.iter().position(|&b| b == 0)?;
+        let keyword = std::str::from_utf8(&data[..null_pos]).ok()?.to_string();
+        let rest = &data[null_pos + 1..];
+        let inflated = |compressed: &[u8]| {
+            inflate(compressed, limit).ok_or_else(|| format!("解压失败或解压后超过 {} 字节", limit))
+        };
+        let utf8 = |bytes: Vec<u8>| {
+            String::from_utf8(bytes).map_err(|_| "文本不是有效的 UTF-8".to_string())
+        };
+
+        let text = match chunk_type {
+            "tEXt" => utf8(rest.to_vec()),
+            "zTXt" => match rest.split_first() {
+                // [压缩方法 1 字节][zlib 数据]，文本为 Latin-1
+                Some((0, compressed)) => {
+                    inflated(compressed).map(|raw| raw.iter().map(|&b| b as char).collect())
+                }
+                Some((method, _)) => Err(format!("不支持的压缩方法 {}", method)),
+                None => Err("缺少压缩方法".to_string()),
+            },
+            "iTXt" => {
+                // [压缩标志][压缩方法][语言标签\0][翻译关键字\0][UTF-8 文本]
+                // 语言标签与翻译关键字对角色卡没有意义，直接跳过
+                let payload = match rest {
+                    [flag, method, tail @ ..] => tail
+                        .iter()
+                        .position(|&b| b == 0)
+                        .map(|lang_end| &tail[lang_end + 1..])
+                        .and_then(|tail| {
+                            let trans_end = tail.iter().position(|&b| b == 0)?;
+                            Some((*flag, *method, &tail[trans_end + 1..]))
+                        }),
+                    _ => None,
+                };
+                match payload {
+                    Some((1, 0, compressed)) => inflated(compressed).and_then(utf8),
+                    Some((1, method, _)) => Err(format!("不支持的压缩方法 {}", method)),
+                    Some((_, _, text)) => utf8(text.to_vec()),
+                    None => Err("iTXt 数据块结构不完整".to_string()),
+                }
+            }
+            _ => return None,
+        };
+        Some((keyword, text))
+    }
+
+    /// 解压 zlib 数据，超过 `limit` 字节视为压缩炸弹，直接放弃
+    fn inflate(compressed: &[u8], limit: usize) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        ZlibDecoder::new(compressed)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut out)
+            .ok()?;
+        (out.len() <= limit).then_some(out)
+    }
+
+    /// 元数据中的角色卡通常为 Base64，部分工具直接写入 JSON 明文
+    fn decode_payload(input: &str, chunk: &'static str) -> Result<String> {
+        let trimmed = input.trim();
+        if trimmed.starts_with('{') {
+            return Ok(trimmed.to_string());
+        }
+        decode_base64(trimmed, chunk)
+    }
+
+    fn decode_base64(input: &str, chunk: &'static str) -> Result<String> {
+        let bytes = general_purpose::STANDARD
+            .decode(input)
+            .map_err(|source| CardError::Base64 { chunk, source })?;
+        String::from_utf8(bytes).map_err(|_| CardError::Utf8 { chunk })
+    }
+
+    /// 生成易读的文本报告
+    /// `regex_issues` 为解析时 `jsregex::check_scripts` 的结果，避免再次编译
+    pub fn format_readable_text(
+        card: &CharacterData,
+        regex_issues: &[jsregex::ScriptIssue],
+    ) -> String {
+        let mut s = String::new();
+        let sep_line = "-".repeat(40);
+        let sep_block = format!("\n{}\n", sep_line);
+
+        // --- 头部信息 ---
+        s.push_str(&format!("【角色名称】: {}\n", card.name));
+        if let Some(nickname) = card.nickname.as_ref().filter(|n| !n.is_empty()) {
+            s.push_str(&format!("【昵    称】: {}\n", nickname));
+        }
+        if !card.creator.is_empty() {
+            s.push_str(&format!("【创 建 者】: {}\n", card.creator));
+        }
+        if !card.character_version.is_empty() {
+            s.push_str(&format!("【版    本】: {}\n", card.character_version));
+        }
+        if !card.tags.is_empty() {
+            s.push_str(&format!("【标    签】: {}\n", card.tags.join(", ")));
+        }
+        if let Some(ts) = card.creation_date {
+            s.push_str(&format!("【创建时间】: {}\n", format_timestamp(ts)));
+        }
+        if let Some(ts) = card.modification_date {
+            s.push_str(&format!("【修改时间】: {}\n", format_timestamp(ts)));
+        }
+        if let Some(source) = card.source.as_ref().filter(|v| !v.is_empty()) {
+            s.push_str(&format!("【来    源】: {}\n", source.join(", ")));
+        }
+
+        // --- 核心设定 ---
+
+        // 描述
+        s.push_str(&sep_block);
+        s.push_str("【角色描述 (Description)】\n\n");
+        s.push_str(&card.description);
+        s.push('\n');
+
+        // 开场白
+        s.push_str(&sep_block);
+        s.push_str("【开场白 (First Message)】\n\n");
+        s.push_str(&card.first_mes);
+        s.push('\n');
+
+        // 备用开场白
+        if !card.alternate_greetings.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【备用开场白 (Alternate Greetings)】\n");
+            for (i, msg) in card.alternate_greetings.iter().enumerate() {
+                s.push_str(&format!("\n# 备用 {}\n{}\n", i + 1, msg));
+            }
+        }
+
+        // 群聊专用开场白 (V3)
+        if !card.group_only_greetings.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【群聊开场白 (Group Only Greetings)】\n");
+            for (i, msg) in card.group_only_greetings.iter().enumerate() {
+                s.push_str(&format!("\n# 群聊 {}\n{}\n", i + 1, msg));
+            }
+        }
+
+        // 性格
+        if !card.personality.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【性格 (Personality)】\n\n");
+            s.push_str(&card.personality);
+            s.push('\n');
+        }
+
+        // 场景
+        if !card.scenario.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【场景 (Scenario)】\n\n");
+            s.push_str(&card.scenario);
+            s.push('\n');
+        }
+
+        // 对话示例
+        if !card.mes_example.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【对话示例 (Example Messages)】\n\n");
+            s.push_str(&card.mes_example);
+            s.push('\n');
+        }
+
+        // --- 高级设定 ---
+
+        // 系统提示词
+        if !card.system_prompt.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【系统提示词 (System Prompt)】\n\n");
+            s.push_str(&card.system_prompt);
+            s.push('\n');
+        }
+
+        if !card.post_history_instructions.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【历史后提示词 (Post History Instructions)】\n\n");
+            s.push_str(&card.post_history_instructions);
+            s.push('\n');
+        }
+
+        // --- 扩展内容 (正则 & 深度提示) ---
+        if let Some(ext) = &card.extensions {
+            // 深度提示词
+            if let Some(dp) = &ext.depth_prompt {
+                s.push_str(&sep_block);
+                s.push_str("【深度提示词 (Depth Prompt)】\n");
+                s.push_str(&format!("Depth: {} | Role: {}\n\n", dp.depth, dp.role));
+                s.push_str(&dp.prompt);
+                s.push('\n');
+            }
+
+            // 正则脚本
+            if !ext.regex_scripts.is_empty() {
+                s.push_str(&sep_block);
+                s.push_str("【正则脚本 (Regex Scripts)】\n");
+                for (i, script) in ext.regex_scripts.iter().enumerate() {
+                    let status = if script.disabled {
+                        "(禁用)"
+                    } else {
+                        "(启用)"
+                    };
+                    s.push_str(&format!(
+                        "\n## {} - {} {}\n",
+                        i + 1,
+                        script.script_name,
+                        status
+                    ));
+                    s.push_str(&format!("作用范围: {}\n", regex_scope(script)));
+                    s.push_str(&format!("Regex: {}\n", script.find_regex));
+                    if let Some(issue) = regex_issues.iter().find(|issue| issue.index == i + 1) {
+                        for e in &issue.errors {
+                            s.push_str(&format!("❌ {}\n", e));
+                        }
+                        for w in &issue.warnings {
+                            s.push_str(&format!("⚠️ {}\n", w));
+                        }
+                    }
+                    if !script.trim_strings.is_empty() {
+                        s.push_str(&format!("Trim: [{}]\n", script.trim_strings.join(", ")));
+                    }
+                    // 替换内容可能很长，只取前200字或者完整显示取决于需求，这里完整显示
+                    s.push_str("Replace:\n");
+                    s.push_str(&script.replace_string);
+                    s.push('\n');
+                }
+            }
+        }
+
+        // --- 世界书 (Character Book) ---
+        // 这是用户特别提到缺失的部分
+        if let Some(book) = &card.character_book {
+            push_world_info(&mut s, book);
+        }
+
+        // --- 作者注释 (通常包含更新日志和玩法指南) ---
+        // 放在最后，类似附录
+        if !card.creator_notes.is_empty() {
+            s.push_str(&sep_block);
+            s.push_str("【作者注释 (Creator Notes)】\n\n");
+            s.push_str(&card.creator_notes);
+            s.push('\n');
+        }
+
+        // 多语言作者注释 (V3)，键为 ISO 639-1 语言代码
+        if let Some(notes) = &card.creator_notes_multilingual {
+            for (lang, note) in notes.iter().filter(|(_, n)| !n.is_empty()) {
+                s.push_str(&sep_block);
+                s.push_str(&format!("【作者注释 - {}】\n\n", lang));
+                s.push_str(note);
+                s.push('\n');
+            }
+        }
+
+        // 资源声明 (V3)
+        if let Some(assets) = card.assets.as_ref().filter(|a| !a.is_empty()) {
+            s.push_str(&sep_block);
+            s.push_str(&format!(
+                "【资源声明 (Assets)】 - 共 {} 个\n\n",
+                assets.len()
+            ));
+            for asset in assets {
+                s.push_str(&format!(
+                    "[{}] {} ({})\n",
+                    asset.asset_type,
+                    if asset.name.is_empty() {
+                        "-"
+                    } else {
+                        &asset.name
+                    },
+                    uri_scheme(&asset.uri)
+                ));
+            }
+        }
+
+        s
+    }
+
+    /// 资源 URI 的来源说明，不输出完整地址 (data: URL 可能长达数 MB)
+    fn uri_scheme(uri: &str) -> String {
+        if uri == "ccdefault:" {
+            return "ccdefault: 默认头像".to_string();
+        }
+        match uri.split_once(':') {
+            Some(("embeded", _)) => format!("CharX 内嵌: {}", uri.trim_start_matches("embeded://")),
+            Some(("data", _)) => "data: 内联数据".to_string(),
+            Some(("http" | "https", _)) => uri.to_string(),
+            Some((scheme, _)) => format!("{}:", scheme),
+            None => "未知".to_string(),
+        }
+    }
+
+    /// Unix 时间戳转本地时间；超过 1e11 的值按毫秒处理 (部分工具写入毫秒)
+    fn format_timestamp(ts: i64) -> String {
+        let secs = if ts.abs() > 100_000_000_000 {
+            ts / 1000
+        } else {
+            ts
+        };
+        match kovi::chrono::DateTime::from_timestamp(secs, 0) {
+            Some(dt) => dt
+                .with_timezone(&kovi::chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            None => ts.to_string(),
+        }
+    }
+
+    /// 生成独立世界书的易读文本报告
+    pub fn format_lorebook_text(book: &CharacterBook) -> String {
+        let mut s = String::new();
+
+        s.push_str(&format!("【世界书名称】: {}\n", book.name));
+        if let Some(depth) = book.scan_depth {
+            s.push_str(&format!("【扫描深度】: {}\n", depth));
+        }
+        if let Some(desc) = &book.description
+            && !desc.is_empty()
+        {
+            s.push_str(&format!("\n{}\n", desc));
+        }
+
+        push_world_info(&mut s, book);
+        s
+    }
+
+    /// 世界书条目部分，角色卡与独立世界书共用
+    fn push_world_info(s: &mut String, book: &CharacterBook) {
+        if book.entries.is_empty() {
+            return;
+        }
+        let sep_block = format!("\n{}\n", "-".repeat(40));
+
+        s.push_str(&sep_block);
+        s.push_str(&format!(
+            "【世界书 / 设定集 (World Info)】 - 共 {} 条\n",
+            book.entries.len()
+        ));
+
+        // 按插入顺序排序，方便阅读
+        let mut entries = book.entries.clone();
+        entries.sort_by_key(|e| e.insertion_order);
+
+        for entry in entries {
+            let status = if !entry.enabled { "[未启用] " } else { "" };
+            s.push_str(&format!(
+                "\n>> {}Key: [{}]\n",
+                status,
+                entry.keys.join(", ")
+            ));
+            if !entry.comment.is_empty() {
+                s.push_str(&format!("注释: {}\n", entry.comment));
+            }
+            let settings = lore_settings(&entry);
+            if !settings.is_empty() {
+                s.push_str(&format!("设置: {}\n", settings.join(" | ")));
+            }
+            let content = entry.decorated_content();
+            if !content.decorators.is_empty() {
+                s.push_str("激活规则:\n");
+                for decorator in &content.decorators {
+                    s.push_str(&format!("  - {}\n", decorator.kind));
+                    for fallback in &decorator.fallbacks {
+                        s.push_str(&format!("    (不支持时改用: {})\n", fallback));
+                    }
+                }
+            }
+            s.push_str("内容:\n");
+            s.push_str(content.body);
+            s.push('\n');
+        }
+    }
+
+    /// 正则脚本的作用范围，如 “AI 输出 | 仅显示 | 深度 0–2”
+    fn regex_scope(script: &RegexScript) -> String {
+        let targets: Vec<&str> = script
+            .placement
+            .iter()
+            .map(|&p| jsregex::placement_name(p))
+            .collect();
+        let mut items = vec![if targets.is_empty() {
+            "未指定作用位置 (不会生效)".to_string()
+        } else {
+            targets.join(", ")
+        }];
+
+        // markdownOnly / promptOnly 都不勾选时会直接修改聊天记录
+        match (script.markdown_only, script.prompt_only) {
+            (true, true) => items.push("仅显示 + 仅提示词".to_string()),
+            (true, false) => items.push("仅显示".to_string()),
+            (false, true) => items.push("仅提示词".to_string()),
+            (false, false) => items.push("修改聊天记录".to_string()),
+        }
+
+        match (script.min_depth, script.max_depth) {
+            (None, None) => {}
+            (min, max) => items.push(format!(
+                "深度 {}–{}",
+                min.map_or("0".to_string(), |d| d.to_string()),
+                max.map_or("∞".to_string(), |d| d.to_string())
+            )),
+        }
+        match script.substitute_regex {
+            1 => items.push("查找正则替换宏".to_string()),
+            2 => items.push("查找正则替换宏 (转义)".to_string()),
+            _ => {}
+        }
+        if script.run_on_edit {
+            items.push("编辑时运行".to_string());
+        }
+        items.join(" | ")
+    }
+
+    /// 条目的激活设置摘要，只列出与默认值不同的项
+    fn lore_settings(entry: &LoreEntry) -> Vec<String> {
+        let ext = &entry.extensions;
+        let mut items = Vec::new();
+
+        if entry.constant {
+            items.push("常驻".to_string());
+        }
+        // 酒馆新建条目的默认顺序为 100
+        if entry.insertion_order != 100 {
+            items.push(format!("顺序 {}", entry.insertion_order));
+        }
+
+        let position = match ext.position {
+            Some(0) => "角色定义前".to_string(),
+            Some(1) => "角色定义后".to_string(),
+            Some(2) => "作者注释前".to_string(),
+            Some(3) => "作者注释后".to_string(),
+            Some(4) => {
+                let role = match ext.role {
+                    Some(1) => "user",
+                    Some(2) => "assistant",
+                    _ => "system",
+                };
+                format!("深度 {} ({})", ext.depth.unwrap_or(4), role)
+            }
+            Some(5) => "示例前".to_string(),
+            Some(6) => "示例后".to_string(),
+            Some(p) => format!("位置 {}", p),
+            None => entry.position.clone(),
+        };
+        if !position.is_empty() {
+            items.push(position);
+        }
+
+        if ext.use_probability != Some(false)
+            && let Some(p) = ext.probability.filter(|&p| p < 100)
+        {
+            items.push(format!("概率 {}%", p));
+        }
+        if entry.selective && !entry.secondary_keys.is_empty() {
+            let logic = match ext.selective_logic.unwrap_or(0) {
+                1 => "NOT ALL",
+                2 => "NOT ANY",
+                3 => "AND ALL",
+                _ => "AND ANY",
+            };
+            items.push(format!(
+                "次要关键词 {} [{}]",
+                logic,
+                entry.secondary_keys.join(", ")
+            ));
+        }
+        if let Some(group) = ext.group.as_ref().filter(|g| !g.is_empty()) {
+            let mut g = format!("分组 {}", group);
+            if let Some(w) = ext.group_weight {
+                g.push_str(&format!(" (权重 {})", w));
+            }
+            if ext.group_override == Some(true) {
+                g.push_str(" 优先");
+            }
+            items.push(g);
+        }
+        for (value, label) in [
+            (ext.sticky, "粘性"),
+            (ext.cooldown, "冷却"),
+            (ext.delay, "延迟"),
+        ] {
+            if let Some(n) = value.filter(|&n| n > 0) {
+                items.push(format!("{} {}", label, n));
+            }
+        }
+        if let Some(depth) = ext.scan_depth {
+            items.push(format!("扫描深度 {}", depth));
+        }
+        for (flag, label) in [
+            (ext.exclude_recursion, "不被递归激活"),
+            (ext.prevent_recursion, "阻止递归"),
+            (ext.match_whole_words, "全词匹配"),
+            (ext.case_sensitive, "区分大小写"),
+            (Some(entry.use_regex), "正则关键词"),
+        ] {
+            if flag == Some(true) {
+                items.push(label.to_string());
+            }
+        }
+        items
+    }
+
+    /// 生成 CharX 内嵌资源列表，附加在文本报告末尾
+    pub fn format_embedded_assets(assets: &[EmbeddedAsset]) -> String {
+        let mut s = String::new();
+        s.push_str(&format!("\n{}\n", "-".repeat(40)));
+        s.push_str(&format!(
+            "【内嵌资源 (CharX Assets)】 - 共 {} 个\n\n",
+            assets.len()
+        ));
+        for asset in assets {
             s.push_str(&format!(
-                "【世界书 / 设定集 (World Info)】 - 共 {} 条\n",
-                book.entries.len()
+                "{} ({:.1} KB)\n",
+                asset.path,
+                asset.size as f64 / 1024.0
+            ));
+        }
+        s
+    }
+
+    /// 列出读取时自动修正的字段，方便作者回头修好原卡
+    pub fn format_coercions(coercions: &[String]) -> String {
+        let mut s = String::new();
+        s.push_str(&format!("\n{}\n", "-".repeat(40)));
+        s.push_str(&format!(
+            "【字段类型修正】 - 共 {} 处\n以下字段类型与规范不符，已自动转换后读取：\n\n",
+            coercions.len()
+        ));
+        for c in coercions {
+            s.push_str(&format!("{}\n", c));
+        }
+        s
+    }
+
+    #[cfg(test)]
+    mod tests {
+        //! PNG 读取的模糊测试语料：截断、随机篡改、超长长度与压缩炸弹。
+        //! 性质：任何输入都不能 panic，也不能按声明的长度分配内存。
+
+        use super::*;
+        use flate2::Compression;
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+
+        /// 1x1 灰度 PNG 头像
+        fn avatar() -> Vec<u8> {
+            let mut ihdr = Vec::new();
+            ihdr.extend_from_slice(&1u32.to_be_bytes());
+            ihdr.extend_from_slice(&1u32.to_be_bytes());
+            ihdr.extend_from_slice(&[8, 0, 0, 0, 0]);
+            let mut idat = ZlibEncoder::new(Vec::new(), Compression::default());
+            idat.write_all(&[0, 0]).unwrap();
+
+            let mut png = PNG_SIGNATURE.to_vec();
+            write_chunk(&mut png, b"IHDR", &ihdr);
+            write_chunk(&mut png, b"IDAT", &idat.finish().unwrap());
+            write_chunk(&mut png, b"IEND", &[]);
+            png
+        }
+
+        fn card_png() -> Vec<u8> {
+            let card = CharacterData {
+                name: "测试".to_string(),
+                description: "模糊测试用角色".to_string(),
+                ..Default::default()
+            };
+            write_card_png(&avatar(), &card, &strict()).unwrap()
+        }
+
+        /// 固定种子的 xorshift，保证语料可复现
+        struct Rng(u64);
+
+        impl Rng {
+            fn next(&mut self) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0
+            }
+
+            fn below(&mut self, n: usize) -> usize {
+                (self.next() % n as u64) as usize
+            }
+        }
+
+        fn strict() -> PngLimits {
+            PngLimits {
+                verify_crc: true,
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn valid_card_parses() {
+            let parsed = parse_card(&card_png(), &strict()).unwrap();
+            assert_eq!(parsed.data.name, "测试");
+        }
+
+        #[test]
+        fn every_truncation_is_an_error() {
+            let png = card_png();
+            for len in 0..png.len() {
+                assert!(
+                    parse_png(&png[..len], &PngLimits::default()).is_err(),
+                    "截断到 {} 字节时应当报错",
+                    len
+                );
+            }
+        }
+
+        #[test]
+        fn data_after_iend_is_ignored() {
+            let mut png = card_png();
+            png.extend_from_slice(&[0xFF; 64]);
+            assert!(parse_png(&png, &strict()).is_ok());
+        }
+
+        #[test]
+        fn missing_iend_is_an_error() {
+            let png = card_png();
+            let err = parse_png(&png[..png.len() - 12], &PngLimits::default())
+                .err()
+                .expect("缺少 IEND 时应当报错");
+            assert!(matches!(err, CardError::MissingIend));
+        }
+
+        #[test]
+        fn corrupt_ccv3_falls_back_to_chara() {
+            let mut png = avatar();
+            let iend = png.len() - 12;
+            let mut chunks = Vec::new();
+            write_chunk(
+                &mut chunks,
+                b"tEXt",
+                &text_chunk_data("ccv3", "不是 Base64"),
+            );
+            let chara = general_purpose::STANDARD.encode(r#"{"name":"备用"}"#);
+            write_chunk(&mut chunks, b"tEXt", &text_chunk_data("chara", &chara));
+            png.splice(iend..iend, chunks);
+
+            let parsed = parse_png(&png, &strict()).unwrap();
+            assert_eq!(parsed.data.name, "备用");
+            assert_eq!(parsed.source, "chara (tEXt)");
+            assert_eq!(parsed.skipped_payloads.len(), 1);
+            assert!(parsed.skipped_payloads[0].starts_with("ccv3 (tEXt)"));
+        }
+
+        /// 在头像的 IEND 前插入 ccv3 与 chara 两份数据
+        fn png_with_payloads(ccv3: &str, chara: &str) -> Vec<u8> {
+            let mut png = avatar();
+            let iend = png.len() - 12;
+            let mut chunks = Vec::new();
+            for (keyword, json) in [("chara", chara), ("ccv3", ccv3)] {
+                let text = general_purpose::STANDARD.encode(json);
+                write_chunk(&mut chunks, b"tEXt", &text_chunk_data(keyword, &text));
+            }
+            png.splice(iend..iend, chunks);
+            png
+        }
+
+        #[test]
+        fn diverging_payloads_are_reported_by_field() {
+            let png = png_with_payloads(
+                r#"{"spec":"chara_card_v3","spec_version":"3.0","data":{
+                    "name":"新名字","description":"相同","first_mes":"你好",
+                    "tags":["甲","乙"],"nickname":"只有 V3 有"}}"#,
+                r#"{"spec":"chara_card_v2","spec_version":"2.0","data":{
+                    "name":"旧名字","description":"相同","first_mes":"你好呀",
+                    "tags":["乙","甲"]}}"#,
+            );
+            let parsed = parse_png(&png, &strict()).unwrap();
+            assert_eq!(parsed.data.name, "新名字");
+            let fields: Vec<_> = parsed.divergence.iter().map(|d| d.field).collect();
+            assert_eq!(fields, ["名称", "开场白"]);
+        }
+
+        fn zlib(bytes: &[u8]) -> Vec<u8> {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        }
+
+        /// 文本数据块的各种编码：(数据块类型, 说明, 数据块内容)
+        fn text_chunk_variants(
+            keyword: &str,
+            text: &str,
+        ) -> Vec<(&'static [u8; 4], &'static str, Vec<u8>)> {
+            let head = |extra: &[u8]| [keyword.as_bytes(), &[0], extra].concat();
+            vec![
+                (
+                    b"zTXt",
+                    "zTXt",
+                    [head(&[0]), zlib(text.as_bytes())].concat(),
+                ),
+                (
+                    b"iTXt",
+                    "iTXt 未压缩",
+                    [head(&[0, 0, 0, 0]), text.as_bytes().to_vec()].concat(),
+                ),
+                (
+                    b"iTXt",
+                    "iTXt 压缩",
+                    [head(&[1, 0, 0, 0]), zlib(text.as_bytes())].concat(),
+                ),
+                (
+                    b"iTXt",
+                    "iTXt 带语言标签",
+                    [
+                        head(&[1, 0]),
+                        "zh-CN\0角色\0".as_bytes().to_vec(),
+                        zlib(text.as_bytes()),
+                    ]
+                    .concat(),
+                ),
+            ]
+        }
+
+        #[test]
+        fn compressed_and_international_text_chunks_are_read() {
+            let ccv3 = general_purpose::STANDARD
+                .encode(r#"{"spec":"chara_card_v3","spec_version":"3.0","data":{"name":"新版"}}"#);
+            let chara = general_purpose::STANDARD.encode(r#"{"name":"旧版"}"#);
+
+            for (chunk_type, label, data) in text_chunk_variants("ccv3", &ccv3) {
+                let kind = std::str::from_utf8(chunk_type).unwrap();
+                let (keyword, text) = read_text_chunk(kind, &data, 1 << 20).unwrap();
+                assert_eq!(
+                    (keyword.as_str(), text.as_deref()),
+                    ("ccv3", Ok(ccv3.as_str())),
+                    "{}",
+                    label
+                );
+
+                // 无论哪一份用这种编码、哪一份在前，都以 ccv3 为准
+                for variant_is_ccv3 in [true, false] {
+                    let (variant, plain) = if variant_is_ccv3 {
+                        (("ccv3", &ccv3), ("chara", &chara))
+                    } else {
+                        (("chara", &chara), ("ccv3", &ccv3))
+                    };
+                    let (_, _, variant_data) = text_chunk_variants(variant.0, variant.1)
+                        .into_iter()
+                        .find(|(_, l, _)| *l == label)
+                        .unwrap();
+                    let mut png = avatar();
+                    let iend = png.len() - 12;
+                    let mut chunks = Vec::new();
+                    write_chunk(&mut chunks, chunk_type, &variant_data);
+                    write_chunk(&mut chunks, b"tEXt", &text_chunk_data(plain.0, plain.1));
+                    png.splice(iend..iend, chunks);
+
+                    let parsed = parse_png(&png, &strict()).unwrap();
+                    assert_eq!(parsed.data.name, "新版", "{}", label);
+                    let source = if variant_is_ccv3 {
+                        format!("ccv3 ({})", kind)
+                    } else {
+                        "ccv3 (tEXt)".to_string()
+                    };
+                    assert_eq!(parsed.source, source, "{}", label);
+                    assert!(parsed.skipped_payloads.is_empty(), "{}", label);
+                }
+            }
+        }
+
+        #[test]
+        fn identical_payloads_have_no_divergence() {
+            let parsed = parse_png(&card_png(), &strict()).unwrap();
+            assert!(parsed.divergence.is_empty());
+
+            let same = r#"{"spec":"chara_card_v2","spec_version":"2.0","data":{"name":"同一个"}}"#;
+            let parsed = parse_png(&png_with_payloads(same, same), &strict()).unwrap();
+            assert!(parsed.divergence.is_empty());
+        }
+
+        #[test]
+        fn v1_fields_are_upgraded() {
+            let v1 = r#"{"char_name":"旧卡","char_persona":"人设","world_scenario":"场景",
+                "char_greeting":"你好","example_dialogue":"<START>"}"#;
+            let parsed = parse_card(v1.as_bytes(), &strict()).unwrap();
+            assert!(parsed.upgraded_from_v1);
+            assert!(matches!(parsed.spec, CardSpec::V1));
+            let data = &parsed.data;
+            assert_eq!(
+                [
+                    &data.name,
+                    &data.description,
+                    &data.scenario,
+                    &data.first_mes,
+                    &data.mes_example
+                ],
+                ["旧卡", "人设", "场景", "你好", "<START>"]
+            );
+            assert!(!parsed.json.contains("char_name"));
+
+            // data 中的旧字段同样升级，已有内容的新字段不被覆盖
+            let wrapped = r#"{"spec":"chara_card_v2","spec_version":"2.0","data":{
+                "name":"新名字","char_name":"旧名字","char_greeting":"旧开场白","first_mes":""}}"#;
+            let parsed = parse_card(wrapped.as_bytes(), &strict()).unwrap();
+            assert!(parsed.upgraded_from_v1);
+            assert_eq!(parsed.data.name, "新名字");
+            assert_eq!(parsed.data.first_mes, "旧开场白");
+
+            let parsed = parse_card(
+                r#"{"name":"新卡","first_mes":"你好"}"#.as_bytes(),
+                &strict(),
+            )
+            .unwrap();
+            assert!(!parsed.upgraded_from_v1);
+        }
+
+        #[test]
+        fn writer_reads_the_avatar_with_the_given_limits() {
+            let card = CharacterData {
+                name: "测试".to_string(),
+                ..Default::default()
+            };
+            let tight = PngLimits {
+                max_chunk_size: 8,
+                ..strict()
+            };
+            // IHDR 有 13 字节，超过上限
+            assert!(write_card_png(&avatar(), &card, &tight).is_err());
+
+            let mut corrupt = avatar();
+            let last = corrupt.len() - 13;
+            corrupt[last] ^= 0xFF;
+            assert!(write_card_png(&corrupt, &card, &strict()).is_err());
+            assert!(write_card_png(&corrupt, &card, &PngLimits::default()).is_ok());
+        }
+
+        #[test]
+        fn oversized_chunk_length_is_rejected() {
+            let mut png = avatar();
+            let iend = png.len() - 12;
+            let mut huge = Vec::new();
+            huge.extend_from_slice(&0x7FFF_FFFFu32.to_be_bytes());
+            huge.extend_from_slice(b"tEXt");
+            huge.extend_from_slice(b"chara\0");
+            png.splice(iend..iend, huge);
+
+            let err = parse_png(&png, &PngLimits::default())
+                .err()
+                .expect("超长数据块应当报错");
+            assert!(matches!(
+                err,
+                CardError::ChunkTooLarge {
+                    length: 0x7FFF_FFFF,
+                    ..
+                }
+            ));
+        }
+
+        #[test]
+        fn charx_card_json_is_bounded() {
+            let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            zip.start_file("card.json", zip::write::SimpleFileOptions::default())
+                .unwrap();
+            let padding = " ".repeat(4096);
+            write!(zip, r#"{{"name":"测试"{}}}"#, padding).unwrap();
+            let bytes = zip.finish().unwrap().into_inner();
+
+            let limits = PngLimits {
+                max_total_size: 1024,
+                ..Default::default()
+            };
+            let err = parse_charx(&bytes, &limits)
+                .err()
+                .expect("解压后超过上限应当报错");
+            assert!(matches!(err, CardError::EntryTooLarge { limit: 1024, .. }));
+            assert!(parse_charx(&bytes, &PngLimits::default()).is_ok());
+        }
+
+        #[test]
+        fn charx_read_failure_is_an_archive_error() {
+            let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            let stored = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored);
+            zip.start_file("card.json", stored).unwrap();
+            zip.write_all(br#"{"name":"card"}"#).unwrap();
+            let mut bytes = zip.finish().unwrap().into_inner();
+
+            // 改动内容但不更新 CRC，解压时读取失败
+            let pos = bytes.windows(6).position(|w| w == br#""card""#).unwrap();
+            bytes[pos + 1] = b'C';
+            let err = parse_charx(&bytes, &PngLimits::default())
+                .err()
+                .expect("CRC 不符应当报错");
+            assert!(matches!(err, CardError::Archive(_)), "{:?}", err);
+        }
+
+        /// 只有 EXIF UserComment 一个标签的小端 TIFF
+        fn exif_tiff(comment: &str) -> Vec<u8> {
+            let mut tiff = b"II*\0".to_vec();
+            tiff.extend_from_slice(&8u32.to_le_bytes());
+            // IFD0 (偏移 8): 指向 EXIF IFD
+            tiff.extend_from_slice(&1u16.to_le_bytes());
+            tiff.extend_from_slice(&0x8769u16.to_le_bytes());
+            tiff.extend_from_slice(&4u16.to_le_bytes());
+            tiff.extend_from_slice(&1u32.to_le_bytes());
+            tiff.extend_from_slice(&26u32.to_le_bytes());
+            tiff.extend_from_slice(&0u32.to_le_bytes());
+            // EXIF IFD (偏移 26): UserComment 数据位于偏移 44
+            let body = [b"ASCII\0\0\0".as_slice(), comment.as_bytes()].concat();
+            tiff.extend_from_slice(&1u16.to_le_bytes());
+            tiff.extend_from_slice(&0x9286u16.to_le_bytes());
+            tiff.extend_from_slice(&7u16.to_le_bytes());
+            tiff.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            tiff.extend_from_slice(&44u32.to_le_bytes());
+            tiff.extend_from_slice(&0u32.to_le_bytes());
+            tiff.extend_from_slice(&body);
+            tiff
+        }
+
+        fn webp(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+            let mut body = b"WEBP".to_vec();
+            for (fourcc, data) in chunks {
+                body.extend_from_slice(*fourcc);
+                body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                body.extend_from_slice(data);
+                if data.len() % 2 == 1 {
+                    body.push(0);
+                }
+            }
+            let mut out = b"RIFF".to_vec();
+            out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            out.extend_from_slice(&body);
+            out
+        }
+
+        fn jpeg(app1: &[&[u8]]) -> Vec<u8> {
+            let mut out = vec![0xFF, 0xD8];
+            for data in app1 {
+                out.extend_from_slice(&[0xFF, 0xE1]);
+                out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+                out.extend_from_slice(data);
+            }
+            out.extend_from_slice(&[0xFF, 0xD9]);
+            out
+        }
+
+        #[test]
+        fn xmp_namespace_declaration_is_not_the_payload() {
+            let xmp =
+                r#"<rdf:Description xmlns:ccv3="http://example.com/ccv3/" ccv3:ccv3="eyJ9"/>"#;
+            assert_eq!(find_xmp_value(xmp, "ccv3").as_deref(), Some("eyJ9"));
+
+            let element = r#"<rdf:Description xmlns:chara="http://example.com/"><chara:chara> {&quot;name&quot;:&quot;x&quot;} </chara:chara></rdf:Description>"#;
+            assert_eq!(
+                find_xmp_value(element, "chara").as_deref(),
+                Some(r#"{"name":"x"}"#)
+            );
+        }
+
+        #[test]
+        fn webp_reads_exif_and_xmp() {
+            let exif = exif_tiff(r#"{"name":"EXIF 卡"}"#);
+            let parsed = parse_card(&webp(&[(b"EXIF", &exif)]), &strict()).unwrap();
+            assert_eq!(parsed.data.name, "EXIF 卡");
+            assert_eq!(parsed.source, "EXIF UserComment");
+
+            let chara = general_purpose::STANDARD.encode(r#"{"name":"XMP 卡"}"#);
+            let xmp = format!(
+                r#"<x:xmpmeta xmlns:st="http://example.com/"><rdf:Description st:chara="{}"/></x:xmpmeta>"#,
+                chara
+            );
+            let parsed = parse_card(&webp(&[(b"XMP ", xmp.as_bytes())]), &strict()).unwrap();
+            assert_eq!(parsed.data.name, "XMP 卡");
+            assert_eq!(parsed.source, "XMP chara");
+
+            let err = parse_card(&webp(&[(b"VP8 ", &[0; 11])]), &strict())
+                .err()
+                .expect("没有元数据时应当报错");
+            assert!(matches!(err, CardError::NotFound { .. }));
+        }
+
+        #[test]
+        fn jpeg_reads_app1_segments() {
+            let exif = [b"Exif\0\0".as_slice(), &exif_tiff(r#"{"name":"JPEG 卡"}"#)].concat();
+            let parsed = parse_card(&jpeg(&[&exif]), &strict()).unwrap();
+            assert_eq!(parsed.data.name, "JPEG 卡");
+
+            let xmp = [
+                b"http://ns.adobe.com/xap/1.0/\0".as_slice(),
+                br#"<rdf:Description xmlns:ccv3="http://example.com/"><ccv3:ccv3>{"spec":"chara_card_v3","data":{"name":"V3"}}</ccv3:ccv3></rdf:Description>"#,
+            ]
+            .concat();
+            let parsed = parse_card(&jpeg(&[&xmp]), &strict()).unwrap();
+            assert_eq!(parsed.data.name, "V3");
+            assert_eq!(parsed.spec, CardSpec::V3);
+        }
+
+        #[test]
+        fn standalone_lorebook_has_its_own_spec() {
+            let world = r#"{"entries":{"0":{"uid":0,"key":["猫"],"content":"一只猫"}}}"#;
+            let parsed = parse_card(world.as_bytes(), &strict()).unwrap();
+            assert_eq!(parsed.kind, CardKind::Lorebook);
+            assert_eq!(parsed.spec, CardSpec::Lorebook);
+        }
+
+        #[test]
+        fn json_export_round_trips_without_inventing_keys() {
+            let source = serde_json::json!({
+                "spec": "chara_card_v2",
+                "spec_version": "2.0",
+                "data": {
+                    "name": "测试",
+                    "description": "描述",
+                    "personality": "",
+                    "scenario": "",
+                    "first_mes": "你好",
+                    "mes_example": "",
+                    "creator_notes": "",
+                    "system_prompt": "",
+                    "post_history_instructions": "",
+                    "creator": "",
+                    "character_version": "",
+                    "alternate_greetings": [],
+                    "tags": ["标签"],
+                    "group_only_greetings": [],
+                    "x_editor": {"nested": [1, 2]}
+                },
+                "x_top": true
+            });
+            let parsed = parse_card(source.to_string().as_bytes(), &strict()).unwrap();
+            let exported: serde_json::Value = serde_json::from_str(&parsed.json).unwrap();
+            assert_eq!(exported, source);
+        }
+
+        #[test]
+        fn lore_extensions_accept_null_and_float_settings() {
+            let card = r#"{"spec":"chara_card_v2","data":{"name":"测试","character_book":{"entries":[
+                {"keys":["猫"],"content":"一","extensions":null},
+                {"keys":["狗"],"content":"二","extensions":{"probability":100.0,"depth":3.6,"x":0.5}}
+            ]}}}"#;
+            let parsed = parse_card(card.as_bytes(), &strict()).unwrap();
+            let entries = &parsed.data.character_book.unwrap().entries;
+            assert!(entries[0].extensions.probability.is_none());
+            assert_eq!(entries[1].extensions.probability, Some(100));
+            assert_eq!(entries[1].extensions.depth, Some(4));
+            assert_eq!(entries[1].extensions.extra["x"], 0.5);
+        }
+
+        #[test]
+        fn mistyped_fields_are_converted_and_reported() {
+            let card = r#"{"spec":"chara_card_v2","spec_version":2.0,"data":{
+                "name":"测试","tags":"奇幻, 冒险，女性",
+                "extensions":{"talkativeness":0.5},
+                "character_book":{"entries":[
+                    {"id":"7","keys":["猫"],"content":"一","position":4},
+                    {"id":3000000000,"keys":["狗"],"content":"二","position":0,
+                     "extensions":{"position":2}},
+                    "不是条目"
+                ]}}}"#;
+            let parsed = parse_card(card.as_bytes(), &strict()).unwrap();
+            let has = |needle: &str| parsed.coercions.iter().any(|c| c.contains(needle));
+
+            assert_eq!(parsed.spec_version, "2.0");
+            assert!(has("数字 2.0 已按文本读取"));
+
+            assert_eq!(parsed.data.tags, ["奇幻", "冒险", "女性"]);
+            assert!(has(r#"文本 "奇幻, 冒险，女性" 已按列表读取"#));
+
+            let ext = parsed.data.extensions.as_ref().unwrap();
+            assert_eq!(ext.talkativeness, "0.5");
+            assert!(has("数字 0.5 已按文本读取"));
+
+            let entries = &parsed.data.character_book.as_ref().unwrap().entries;
+            assert_eq!(entries.len(), 2);
+            assert!(has(r#"文本 "不是条目" 不是对象，已忽略"#));
+
+            assert_eq!(entries[0].id, Some(7));
+            assert!(has(r#"文本 "7" 已按整数读取"#));
+            assert_eq!(entries[1].id, Some(i32::MAX));
+            assert!(has("数字 3000000000 超出整数范围，已按 2147483647 读取"));
+
+            // 整数位置改为 before_char / after_char，已有的 extensions.position 不覆盖
+            assert_eq!(entries[0].position, "after_char");
+            assert_eq!(entries[0].extensions.position, Some(4));
+            assert!(has(
+                "数字位置 4 已按 after_char 读取，原值记入 extensions.position"
             ));
+            assert_eq!(entries[1].position, "before_char");
+            assert_eq!(entries[1].extensions.position, Some(2));
+            assert!(has("数字位置 0 已按 before_char 读取"));
+
+            assert_eq!(parsed.coercions.len(), 8, "{:#?}", parsed.coercions);
+        }
 
-            // 按插入顺序排序，方便阅读
-            let mut entries = book.entries.clone();
-            entries.sort_by_key(|e| e.insertion_order);
+        #[test]
+        fn regex_scripts_without_depth_limits() {
+            let card = r#"{"spec":"chara_card_v2","data":{"name":"测试","extensions":{
+                "regex_scripts":[
+                    {"scriptName":"旧","findRegex":"/a/g","substituteRegex":true},
+                    {"scriptName":"新","findRegex":"/b/g","substituteRegex":"2","maxDepth":-1}
+                ]}}}"#;
+            let parsed = parse_card(card.as_bytes(), &strict()).unwrap();
+            let scripts = &parsed.data.extensions.as_ref().unwrap().regex_scripts;
+            assert_eq!(scripts.len(), 2);
+            assert_eq!((scripts[0].min_depth, scripts[0].max_depth), (None, None));
+            assert_eq!(scripts[0].substitute_regex, 1);
+            assert_eq!(scripts[1].substitute_regex, 2);
+            assert_eq!(scripts[1].max_depth, Some(-1));
+            assert_eq!(parsed.coercions, [r#"文本 "2" 已按整数读取"#]);
+        }
 
-            for entry in entries {
-                let status = if !entry.enabled { "[未启用] " } else { "" };
-                s.push_str(&format!(
-                    "\n>> {}Key: [{}]\n",
-                    status,
-                    entry.keys.join(", ")
-                ));
-                if !entry.comment.is_empty() {
-                    s.push_str(&format!("注释: {}\n", entry.comment));
-                }
-                s.push_str("内容:\n");
-                s.push_str(&entry.content);
-                s.push('\n');
-            }
+        #[test]
+        fn well_typed_cards_report_nothing() {
+            let parsed = parse_card(&card_png(), &strict()).unwrap();
+            assert!(parsed.coercions.is_empty());
+
+            let world =
+                r#"{"entries":{"0":{"uid":0,"key":["猫"],"content":"一","probability":null}}}"#;
+            let parsed = parse_card(world.as_bytes(), &strict()).unwrap();
+            assert!(parsed.coercions.is_empty());
+            let book = parsed.data.character_book.unwrap();
+            assert_eq!(book.entries[0].keys, ["猫"]);
         }
 
-        // --- 作者注释 (通常包含更新日志和玩法指南) ---
-        // 放在最后，类似附录
-        if !card.creator_notes.is_empty() {
-            s.push_str(&sep_block);
-            s.push_str("【作者注释 (Creator Notes)】\n\n");
-            s.push_str(&card.creator_notes);
-            s.push('\n');
+        #[test]
+        fn total_size_limit_is_enforced() {
+            let limits = PngLimits {
+                max_total_size: 16,
+                ..Default::default()
+            };
+            assert!(parse_png(&card_png(), &limits).is_err());
         }
 
-        s
+        #[test]
+        fn compression_bomb_is_bounded() {
+            let mut bomb = ZlibEncoder::new(Vec::new(), Compression::best());
+            bomb.write_all(&vec![b'A'; 8 * 1024 * 1024]).unwrap();
+            let mut data = b"chara\0\0".to_vec();
+            data.extend_from_slice(&bomb.finish().unwrap());
+
+            let mut png = avatar();
+            let iend = png.len() - 12;
+            let mut chunk = Vec::new();
+            write_chunk(&mut chunk, b"zTXt", &data);
+            png.splice(iend..iend, chunk);
+
+            let limits = PngLimits {
+                max_chunk_size: 1024 * 1024,
+                ..Default::default()
+            };
+            assert!(parse_png(&png, &limits).is_err());
+        }
+
+        #[test]
+        fn single_bit_flips_fail_crc_check() {
+            let png = card_png();
+            let mut rng = Rng(0x5EED_CA4D);
+            for _ in 0..2000 {
+                let mut mutated = png.clone();
+                let i = PNG_SIGNATURE.len() + rng.below(png.len() - PNG_SIGNATURE.len());
+                mutated[i] ^= 1 << rng.below(8);
+                assert!(
+                    parse_png(&mutated, &strict()).is_err(),
+                    "翻转第 {} 字节应当报错",
+                    i
+                );
+            }
+        }
+
+        #[test]
+        fn random_mutations_never_panic() {
+            let png = card_png();
+            let mut rng = Rng(0xC0FF_EE00_1234_5678);
+            for round in 0..3000 {
+                let mut mutated = png.clone();
+                for _ in 0..=rng.below(8) {
+                    let i = rng.below(mutated.len());
+                    match rng.below(4) {
+                        0 => mutated[i] = rng.next() as u8,
+                        1 => mutated.truncate(i),
+                        2 => mutated.insert(i, rng.next() as u8),
+                        // 把某处改写成巨大的长度字段
+                        _ => {
+                            let end = (i + 4).min(mutated.len());
+                            mutated[i..end].fill(0xFF);
+                        }
+                    }
+                    if mutated.is_empty() {
+                        break;
+                    }
+                }
+                let limits = if round % 2 == 0 {
+                    strict()
+                } else {
+                    PngLimits::default()
+                };
+                let _ = parse_card(&mutated, &limits);
+            }
+        }
     }
 }
 
 mod utils {
-    use kovi::MsgEvent;
+    use kovi::serde_json::Value;
+    use kovi::{MsgEvent, log};
+    use kovi_plugin_expand_napcat::NapCatApi;
+    use std::path::Path;
     use std::sync::Arc;
 
-    pub async fn get_image_url(
+    /// 收集消息与引用消息中所有图片 / 文件的下载地址，当前消息在前
+    /// 支持图片消息和文件消息 (CharX 等非图片格式只能以文件形式发送)
+    pub async fn get_attachment_urls(
         event: &Arc<MsgEvent>,
         bot: &Arc<kovi::RuntimeBot>,
-    ) -> Option<String> {
+    ) -> Vec<String> {
+        let mut urls = Vec::new();
+
         // 1. 检查当前消息
         for seg in event.message.iter() {
-            if seg.type_ == "image"
-                && let Some(url) = seg.data.get("url").and_then(|u| u.as_str())
-            {
-                return Some(url.to_string());
+            if let Some(url) = segment_url(&seg.type_, &seg.data, event, bot).await {
+                urls.push(url);
             }
         }
 
@@ -497,28 +6073,128 @@ mod utils {
             } else {
                 None
             }
-        })?;
+        });
 
-        if let Ok(reply_id_int) = reply_id.parse::<i32>()
+        if let Some(reply_id) = reply_id
+            && let Ok(reply_id_int) = reply_id.parse::<i32>()
             && let Ok(msg_res) = bot.get_msg(reply_id_int).await
             && let Some(segments) = msg_res.data.get("message").and_then(|v| v.as_array())
         {
             for seg in segments {
                 if let Some(type_) = seg.get("type").and_then(|t| t.as_str())
-                    && type_ == "image"
-                    && let Some(url) = seg
-                        .get("data")
-                        .and_then(|d| d.get("url"))
-                        .and_then(|u| u.as_str())
+                    && let Some(data) = seg.get("data")
+                    && let Some(url) = segment_url(type_, data, event, bot).await
                 {
-                    return Some(url.to_string());
+                    urls.push(url);
                 }
             }
         }
-        None
+        urls
+    }
+
+    /// 从单个消息段中取出下载地址
+    async fn segment_url(
+        type_: &str,
+        data: &Value,
+        event: &Arc<MsgEvent>,
+        bot: &Arc<kovi::RuntimeBot>,
+    ) -> Option<String> {
+        if type_ != "image" && type_ != "file" {
+            return None;
+        }
+        if let Some(url) = data.get("url").and_then(|u| u.as_str())
+            && !url.is_empty()
+        {
+            return Some(url.to_string());
+        }
+        if type_ != "file" {
+            return None;
+        }
+
+        // 文件消息通常不带 url，需要通过 file_id 换取
+        let file_id = data.get("file_id").and_then(|v| v.as_str())?;
+        let res = match event.group_id {
+            Some(group_id) => bot.get_group_file_url(group_id, file_id).await,
+            None => bot.get_file(file_id).await,
+        }
+        .ok()?;
+        res.data
+            .get("url")
+            .and_then(|u| u.as_str())
+            .map(|u| u.to_string())
+    }
+
+    /// 下载文件，失败时返回可直接回复给用户的提示
+    pub async fn download(url: &str) -> Result<Vec<u8>, String> {
+        match reqwest::get(url).await {
+            Ok(resp) => match resp.bytes().await {
+                Ok(b) => Ok(b.to_vec()),
+                Err(e) => Err(format!("❌ 图片下载失败: {}", e)),
+            },
+            Err(e) => Err(format!("❌ 网络请求失败: {}", e)),
+        }
+    }
+
+    /// 上传文件到当前群聊或私聊，返回是否成功
+    pub async fn upload_file(
+        bot: &Arc<kovi::RuntimeBot>,
+        event: &Arc<MsgEvent>,
+        path: &Path,
+        name: &str,
+    ) -> bool {
+        let path_str = path.to_string_lossy().to_string();
+        let result = match event.group_id {
+            Some(group_id) => bot.upload_group_file(group_id, &path_str, name, None).await,
+            None => {
+                bot.upload_private_file(event.user_id, &path_str, name)
+                    .await
+            }
+        };
+        if let Err(e) = result {
+            log::error!("Failed to upload file {}: {}", name, e);
+            return false;
+        }
+        true
+    }
+
+    /// 把角色名转换为安全的文件名
+    pub fn safe_file_name(name: &str) -> String {
+        let safe_name = name.replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "_");
+        if safe_name.trim().is_empty() {
+            "character".to_string()
+        } else {
+            safe_name
+        }
+    }
+
+    /// 读卡指令可以附带的选项词 (小写)
+    const READ_OPTIONS: &[&str] = &[
+        "世界书",
+        "world",
+        "正则",
+        "regex",
+        "诊断",
+        "lint",
+        "表格",
+        "csv",
+        "xlsx",
+    ];
+
+    /// 把读卡指令的参数拆成选项词；有不认识的词时视为普通聊天，返回 None
+    pub fn read_options(args: &str) -> Option<Vec<String>> {
+        let options: Vec<String> = args.split_whitespace().map(str::to_lowercase).collect();
+        options
+            .iter()
+            .all(|o| READ_OPTIONS.contains(&o.as_str()))
+            .then_some(options)
     }
 
-    pub fn parse_command(text: &str, prefixes: &[String], commands: &[String]) -> bool {
+    /// 匹配指令，返回指令之后的参数 (可能为空)；未匹配时返回 None
+    pub fn parse_command<'a>(
+        text: &'a str,
+        prefixes: &[String],
+        commands: &[String],
+    ) -> Option<&'a str> {
         let text = text.trim();
         let clean_text = if !prefixes.is_empty() {
             let mut found = None;
@@ -531,14 +6207,54 @@ mod utils {
                     break;
                 }
             }
-            match found {
-                Some(t) => t.trim(),
-                None => return false,
-            }
+            found?.trim()
         } else {
             text
         };
-        commands.contains(&clean_text.to_string())
+
+        // 优先匹配更长的指令，指令后必须是空白或结尾
+        let mut sorted_commands = commands.to_vec();
+        sorted_commands.sort_by_key(|b| std::cmp::Reverse(b.len()));
+        sorted_commands.iter().find_map(|cmd| {
+            let rest = clean_text.strip_prefix(cmd.as_str())?;
+            if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                Some(rest.trim())
+            } else {
+                None
+            }
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn read_command_only_takes_option_words() {
+            let commands = ["card".to_string(), "读卡".to_string()];
+            let read = |text| parse_command(text, &[], &commands).and_then(read_options);
+
+            assert_eq!(read("card"), Some(Vec::new()));
+            assert_eq!(
+                read("读卡 世界书 XLSX"),
+                Some(vec!["世界书".into(), "xlsx".into()])
+            );
+            // 普通聊天不响应
+            assert_eq!(read("card game tonight?"), None);
+            assert_eq!(read("读卡 世界书 谢谢"), None);
+            assert_eq!(read("cards"), None);
+        }
+
+        #[test]
+        fn prefixes_and_longest_command() {
+            let prefixes = ["/".to_string()];
+            let commands = ["读".to_string(), "读卡".to_string()];
+            assert_eq!(
+                parse_command(" /读卡  表格 ", &prefixes, &commands),
+                Some("表格")
+            );
+            assert_eq!(parse_command("读卡", &prefixes, &commands), None);
+        }
     }
 }
 
@@ -546,10 +6262,12 @@ mod utils {
 //      Main Plugin Logic
 // =============================
 
-use kovi::{PluginBuilder, log};
-use kovi_plugin_expand_napcat::NapCatApi;
+use error::CardError;
+use kovi::serde_json;
+use kovi::{MsgEvent, PluginBuilder, RuntimeBot};
 use std::fs::File;
 use std::io::Write;
+use std::sync::Arc;
 
 #[kovi::plugin]
 async fn main() {
@@ -569,164 +6287,781 @@ async fn main() {
                 None => return,
             };
 
-            let (commands, prefixes, enabled, text_preview) = {
-                let cfg = config_lock.read().unwrap();
-                (
-                    cfg.commands.clone(),
-                    cfg.prefixes.clone(),
-                    cfg.enabled,
-                    cfg.text_preview,
-                )
-            };
+            let cfg = config_lock.read().unwrap().clone();
 
-            if !enabled {
+            if !cfg.enabled {
                 return;
             }
 
-            // 1. 匹配指令
-            if utils::parse_command(text, &prefixes, &commands) {
-                // 2. 获取图片
-                let img_url = match utils::get_image_url(&event, &bot).await {
-                    Some(u) => u,
-                    None => {
-                        event.reply("⚠️ 请附带角色卡图片或引用图片消息");
-                        return;
-                    }
-                };
+            // 匹配指令
+            if let Some(options) = utils::parse_command(text, &cfg.prefixes, &cfg.commands)
+                .and_then(utils::read_options)
+            {
+                handle_read(&event, &bot, &cfg, &options).await;
+            } else if utils::parse_command(text, &cfg.prefixes, &cfg.pack_commands).is_some() {
+                handle_pack(&event, &bot, &cfg).await;
+            } else if let Some(args) =
+                utils::parse_command(text, &cfg.prefixes, &cfg.regex_test_commands)
+            {
+                handle_regex_test(&event, &bot, &cfg, args).await;
+            } else if let Some(args) =
+                utils::parse_command(text, &cfg.prefixes, &cfg.lore_test_commands)
+            {
+                handle_lore_test(&event, &bot, &cfg, args).await;
+            } else if utils::parse_command(text, &cfg.prefixes, &cfg.sheet_import_commands)
+                .is_some()
+            {
+                handle_sheet_import(&event, &bot, &cfg).await;
+            }
+        }
+    });
 
-                event.reply("🔍 正在读取角色卡，请稍候...");
+    PluginBuilder::drop(move || {
+        let config_lock = config::get();
+        async move {
+            let config = {
+                let guard = config_lock.read().unwrap();
+                guard.clone()
+            };
+            config.save();
+        }
+    });
+}
 
-                // 3. 下载图片
-                let img_bytes = match reqwest::get(&img_url).await {
-                    Ok(resp) => match resp.bytes().await {
-                        Ok(b) => b,
-                        Err(e) => {
-                            event.reply(format!("❌ 图片下载失败: {}", e));
-                            return;
-                        }
-                    },
-                    Err(e) => {
-                        event.reply(format!("❌ 网络请求失败: {}", e));
-                        return;
-                    }
-                };
+/// 读卡：解析角色卡，上传 JSON 与 TXT
+///
+/// 指令后只能跟选项词 (按空白拆分，整词比较，不区分大小写)，否则不响应：
+/// 有“世界书”或 `world` 时 (或配置中开启 export_world)，
+/// 额外把内嵌世界书导出为酒馆世界书文件；
+/// 有“正则”或 `regex` 时 (或开启 export_regex)，额外导出正则脚本压缩包；
+/// 有“诊断”或 `lint` 时 (或开启 lore_diagnostics)，在 TXT 末尾附加世界书诊断；
+/// 有“表格”、`csv` 或 `xlsx` 时 (或开启 export_sheet)，额外导出世界书表格。
+async fn handle_read(
+    event: &Arc<MsgEvent>,
+    bot: &Arc<RuntimeBot>,
+    cfg: &config::Config,
+    options: &[String],
+) {
+    let has_option = |names: &[&str]| options.iter().any(|o| names.contains(&o.as_str()));
+    let export_world = cfg.export_world || has_option(&["世界书", "world"]);
+    let export_regex = cfg.export_regex || has_option(&["正则", "regex"]);
+    let lore_diagnostics = cfg.lore_diagnostics || has_option(&["诊断", "lint"]);
+    let export_sheet = cfg.export_sheet || has_option(&["表格", "csv", "xlsx"]);
 
-                // 4. 解析 PNG
-                let parse_result = parser::parse_png(&img_bytes);
-
-                match parse_result {
-                    Ok((card, json_str)) => {
-                        let safe_name = card
-                            .name
-                            .replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "_");
-                        let safe_name = if safe_name.trim().is_empty() {
-                            "character".to_string()
-                        } else {
-                            safe_name
-                        };
+    // 1. 获取图片或文件
+    let img_url = match utils::get_attachment_urls(event, bot)
+        .await
+        .into_iter()
+        .next()
+    {
+        Some(u) => u,
+        None => {
+            event.reply("⚠️ 请附带角色卡图片，或引用角色卡图片 / 文件消息");
+            return;
+        }
+    };
 
-                        // 5. 生成文件内容 (易读文本)
-                        let readable_text = parser::format_readable_text(&card);
+    event.reply("🔍 正在读取角色卡，请稍候...");
 
-                        // 6. 保存临时文件
-                        let timestamp = kovi::chrono::Local::now().format("%H%M%S").to_string();
-                        let json_filename = format!("{}_{}.json", safe_name, timestamp);
-                        let txt_filename = format!("{}_{}_read.txt", safe_name, timestamp);
+    // 2. 下载图片
+    let img_bytes = match utils::download(&img_url).await {
+        Ok(b) => b,
+        Err(e) => {
+            event.reply(e);
+            return;
+        }
+    };
 
-                        let data_path = bot.get_data_path();
-                        let json_path = data_path.join(&json_filename);
-                        let txt_path = data_path.join(&txt_filename);
+    // 3. 解析 PNG / CharX
+    let parsed = match parser::parse_card(&img_bytes, &cfg.png_limits()) {
+        Ok(p) => p,
+        Err(e) => {
+            event.reply(card_error_reply("解析", &e));
+            return;
+        }
+    };
+    let card = &parsed.data;
+    let safe_name = utils::safe_file_name(&card.name);
 
-                        // 写入 JSON (UTF-8, 无需 BOM 只要编辑器支持即可，但TXT需要)
-                        if let Ok(mut f) = File::create(&json_path) {
-                            let _ = f.write_all(json_str.as_bytes());
-                        }
+    // 4. 生成文件内容 (易读文本)
+    let mut readable_text = match (parsed.kind, &card.character_book) {
+        (parser::CardKind::Lorebook, Some(book)) => parser::format_lorebook_text(book),
+        _ => parser::format_readable_text(card, &parsed.regex_issues),
+    };
+    if !parsed.assets.is_empty() {
+        readable_text.push_str(&parser::format_embedded_assets(&parsed.assets));
+    }
+    if !parsed.divergence.is_empty() {
+        readable_text.push_str(&divergence::report(&parsed.divergence));
+    }
+    if !parsed.coercions.is_empty() {
+        readable_text.push_str(&parser::format_coercions(&parsed.coercions));
+    }
+    if let Some(book) = &card.character_book
+        && lore_diagnostics
+        && !book.entries.is_empty()
+    {
+        readable_text.push_str(&diagnostics::report(book));
+    }
 
-                        // 写入 TXT (UTF-8 with BOM)
-                        // 关键修改：添加 UTF-8 BOM 头 [0xEF, 0xBB, 0xBF]
-                        // 这有助于 Windows 记事本和手机阅读器正确识别编码
-                        if let Ok(mut f) = File::create(&txt_path) {
-                            let bom = [0xEF, 0xBB, 0xBF];
-                            let _ = f.write_all(&bom);
-                            let _ = f.write_all(readable_text.as_bytes());
-                        }
+    // 5. 保存临时文件
+    let timestamp = kovi::chrono::Local::now().format("%H%M%S").to_string();
+    let json_filename = format!("{}_{}.json", safe_name, timestamp);
+    let txt_filename = format!("{}_{}_read.txt", safe_name, timestamp);
 
-                        // 7. 发送文件
-                        let json_path_str = json_path.to_string_lossy().to_string();
-                        let txt_path_str = txt_path.to_string_lossy().to_string();
+    let data_path = bot.get_data_path();
+    let json_path = data_path.join(&json_filename);
+    let txt_path = data_path.join(&txt_filename);
 
-                        let mut success = true;
+    // 写入 JSON (UTF-8, 无需 BOM 只要编辑器支持即可，但TXT需要)
+    if let Ok(mut f) = File::create(&json_path) {
+        let _ = f.write_all(parsed.json.as_bytes());
+    }
 
-                        if let Some(group_id) = event.group_id {
-                            if let Err(e) = bot
-                                .upload_group_file(group_id, &json_path_str, &json_filename, None)
-                                .await
-                            {
-                                log::error!("Failed to upload group file JSON: {}", e);
-                                success = false;
-                            }
-                            kovi::tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                            if let Err(e) = bot
-                                .upload_group_file(group_id, &txt_path_str, &txt_filename, None)
-                                .await
-                            {
-                                log::error!("Failed to upload group file TXT: {}", e);
-                                success = false;
-                            }
-                        } else {
-                            if let Err(e) = bot
-                                .upload_private_file(event.user_id, &json_path_str, &json_filename)
-                                .await
-                            {
-                                log::error!("Failed to upload private file JSON: {}", e);
-                                success = false;
-                            }
-                            kovi::tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                            if let Err(e) = bot
-                                .upload_private_file(event.user_id, &txt_path_str, &txt_filename)
-                                .await
-                            {
-                                log::error!("Failed to upload private file TXT: {}", e);
-                                success = false;
-                            }
-                        }
+    // 写入 TXT (UTF-8 with BOM)
+    // 关键修改：添加 UTF-8 BOM 头 [0xEF, 0xBB, 0xBF]
+    // 这有助于 Windows 记事本和手机阅读器正确识别编码
+    if let Ok(mut f) = File::create(&txt_path) {
+        let bom = [0xEF, 0xBB, 0xBF];
+        let _ = f.write_all(&bom);
+        let _ = f.write_all(readable_text.as_bytes());
+    }
 
-                        if !success {
-                            event.reply("⚠️ 文件上传过程中出现部分错误，请检查日志。");
-                        } else if text_preview {
-                            let preview = format!(
-                                "✅ 解析成功: {}\n作者: {}\n字数: {}\n(详细设定请查看TXT，配置请查看JSON)",
-                                card.name,
-                                if card.creator.is_empty() {
-                                    "未知"
-                                } else {
-                                    &card.creator
-                                },
-                                readable_text.chars().count()
-                            );
-                            event.reply(preview);
-                        }
+    // 世界书文件 (独立世界书的 JSON 本身就是世界书文件，无需重复导出)
+    let world_file = match &card.character_book {
+        Some(book)
+            if export_world
+                && parsed.kind == parser::CardKind::Character
+                && !book.entries.is_empty() =>
+        {
+            let world_filename = format!("{}_{}_world.json", safe_name, timestamp);
+            let world_path = data_path.join(&world_filename);
+            let world = worldinfo::from_character_book(book);
+            match serde_json::to_string_pretty(&world) {
+                Ok(json) if std::fs::write(&world_path, &json).is_ok() => {
+                    Some((world_path, world_filename))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
 
-                        // 8. 删除临时文件
-                        let _ = std::fs::remove_file(&json_path);
-                        let _ = std::fs::remove_file(&txt_path);
-                    }
-                    Err(e) => {
-                        event.reply(format!("❌ 解析失败: {}", e));
-                    }
+    // 正则脚本压缩包
+    let regex_file = match &card.extensions {
+        Some(ext) if export_regex && !ext.regex_scripts.is_empty() => {
+            let regex_filename = format!("{}_{}_regex.zip", safe_name, timestamp);
+            let regex_path = data_path.join(&regex_filename);
+            match regex_preset::export_zip(&ext.regex_scripts) {
+                Ok(bytes) if std::fs::write(&regex_path, &bytes).is_ok() => {
+                    Some((regex_path, regex_filename))
                 }
+                _ => None,
             }
         }
-    });
+        _ => None,
+    };
 
-    PluginBuilder::drop(move || {
-        let config_lock = config::get();
-        async move {
-            let config = {
-                let guard = config_lock.read().unwrap();
-                guard.clone()
+    // 世界书表格 (XLSX 与 CSV 各一份)
+    let mut sheet_files = Vec::new();
+    let mut sheet_notes = Vec::new();
+    if let Some(book) = &card.character_book
+        && export_sheet
+        && !book.entries.is_empty()
+    {
+        let exports = [
+            ("xlsx", sheet::export_xlsx(book)),
+            ("csv", sheet::export_csv(book)),
+        ];
+        for (ext, bytes) in exports {
+            let sheet_filename = format!("{}_{}_lore.{}", safe_name, timestamp, ext);
+            let sheet_path = data_path.join(&sheet_filename);
+            match bytes {
+                Ok(bytes) if std::fs::write(&sheet_path, &bytes).is_ok() => {
+                    sheet_files.push((sheet_path, sheet_filename));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    kovi::log::error!("世界书表格导出失败: {}", e);
+                    sheet_notes.push(format!("⚠️ {}，未导出 _lore.{}，请使用 CSV\n", e, ext));
+                }
+            }
+        }
+    }
+
+    // 6. 发送文件
+    let mut success = utils::upload_file(bot, event, &json_path, &json_filename).await;
+    kovi::tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    success &= utils::upload_file(bot, event, &txt_path, &txt_filename).await;
+    for (path, filename) in world_file
+        .iter()
+        .chain(regex_file.iter())
+        .chain(sheet_files.iter())
+    {
+        kovi::tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        success &= utils::upload_file(bot, event, path, filename).await;
+    }
+
+    if !success {
+        event.reply("⚠️ 文件上传过程中出现部分错误，请检查日志。");
+    } else if cfg.text_preview {
+        let mut preview = if parsed.kind == parser::CardKind::Lorebook {
+            format!(
+                "✅ 解析成功 (世界书): {}\n条目: {}\n字数: {}\n",
+                card.name,
+                card.character_book.as_ref().map_or(0, |b| b.entries.len()),
+                readable_text.chars().count()
+            )
+        } else {
+            let spec = if parsed.spec_version.is_empty() {
+                parsed.spec.to_string()
+            } else {
+                format!("{} ({})", parsed.spec, parsed.spec_version)
             };
-            config.save();
+            format!(
+                "✅ 解析成功: {}\n作者: {}\n规范: {}\n字数: {}\n",
+                card.name,
+                if card.creator.is_empty() {
+                    "未知"
+                } else {
+                    &card.creator
+                },
+                spec,
+                readable_text.chars().count()
+            )
+        };
+        if !parsed.assets.is_empty() {
+            preview.push_str(&format!("内嵌资源: {} 个\n", parsed.assets.len()));
         }
-    });
+        if !parsed.source.is_empty() {
+            preview.push_str(&format!("数据来源: {}\n", parsed.source));
+        }
+        for skipped in &parsed.skipped_payloads {
+            preview.push_str(&format!("⚠️ 已跳过损坏的数据块 {}\n", skipped));
+        }
+        if !parsed.divergence.is_empty() {
+            let fields: Vec<_> = parsed.divergence.iter().map(|d| d.field).collect();
+            preview.push_str(&format!(
+                "⚠️ ccv3 与 chara 内容不一致，不同前端会显示不同内容: {} (详见 TXT)\n",
+                fields.join("、")
+            ));
+        }
+        if parsed.upgraded_from_v1 {
+            preview.push_str("⚠️ 检测到 TavernAI V1 旧字段 (char_name 等)，已自动升级\n");
+        }
+        if let Some(first) = parsed.coercions.first() {
+            preview.push_str(&format!(
+                "⚠️ {} 处字段类型不符，已自动修正 (如 {})，详见 TXT\n",
+                parsed.coercions.len(),
+                first
+            ));
+        }
+        for issue in parsed.regex_issues.iter().take(3) {
+            let reason = issue.errors.first().or(issue.warnings.first());
+            preview.push_str(&format!(
+                "{} 正则 #{} {}: {}\n",
+                if issue.errors.is_empty() {
+                    "⚠️"
+                } else {
+                    "❌"
+                },
+                issue.index,
+                issue.name,
+                reason.map_or("", String::as_str)
+            ));
+        }
+        if parsed.regex_issues.len() > 3 {
+            preview.push_str(&format!(
+                "…另有 {} 个正则脚本存在问题，详见 TXT\n",
+                parsed.regex_issues.len() - 3
+            ));
+        }
+        if world_file.is_some() {
+            preview.push_str("世界书已导出为 _world.json，可直接导入酒馆\n");
+        }
+        if regex_file.is_some() {
+            preview.push_str("正则脚本已打包为 _regex.zip，解压后可在酒馆正则扩展中导入\n");
+        }
+        if !sheet_files.is_empty() {
+            let names: Vec<_> = sheet_files
+                .iter()
+                .filter_map(|(_, name)| name.rsplit_once('_').map(|(_, suffix)| suffix))
+                .map(|suffix| format!("_{}", suffix))
+                .collect();
+            preview.push_str(&format!(
+                "世界书已导出为表格 ({})，编辑后可用表格导入指令合并回卡片\n",
+                names.join(" / ")
+            ));
+        }
+        for note in &sheet_notes {
+            preview.push_str(note);
+        }
+        preview.push_str("(详细设定请查看TXT，配置请查看JSON)");
+        event.reply(preview);
+    }
+
+    // 7. 删除临时文件
+    let _ = std::fs::remove_file(&json_path);
+    let _ = std::fs::remove_file(&txt_path);
+    for (path, _) in world_file
+        .iter()
+        .chain(regex_file.iter())
+        .chain(sheet_files.iter())
+    {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// 打包卡：把角色卡数据重新写入 PNG，上传生成的卡片
+///
+/// 附件 (当前消息 + 引用消息) 中第一个能解析出角色的作为数据来源，
+/// 其余附件中的第一张 PNG 作为头像；没有额外头像时沿用来源卡片本身的图片。
+async fn handle_pack(event: &Arc<MsgEvent>, bot: &Arc<RuntimeBot>, cfg: &config::Config) {
+    let urls = utils::get_attachment_urls(event, bot).await;
+    if urls.is_empty() {
+        event.reply("⚠️ 请附带角色卡 (PNG / JSON / CharX) 以及可选的 PNG 头像，或引用对应消息");
+        return;
+    }
+
+    event.reply("🛠️ 正在打包角色卡，请稍候...");
+
+    let mut files = Vec::new();
+    for url in urls.iter().take(2) {
+        match utils::download(url).await {
+            Ok(b) => files.push(b),
+            Err(e) => {
+                event.reply(e);
+                return;
+            }
+        }
+    }
+
+    // 1. 找到角色数据
+    let limits = cfg.png_limits();
+    let Some((card_index, parsed)) = files.iter().enumerate().find_map(|(i, b)| {
+        parser::parse_card(b, &limits)
+            .ok()
+            .filter(|p| p.kind == parser::CardKind::Character)
+            .map(|p| (i, p))
+    }) else {
+        event.reply("❌ 附件中没有可读取的角色卡");
+        return;
+    };
+
+    // 2. 找到头像：优先使用另一张 PNG，否则使用角色卡自身
+    let avatar = files
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != card_index)
+        .chain(std::iter::once((card_index, &files[card_index])))
+        .map(|(_, b)| b)
+        .find(|b| parser::is_png(b));
+    let Some(avatar) = avatar else {
+        event.reply("❌ 需要一张 PNG 图片作为头像 (WebP / JPEG 请先转换为 PNG)");
+        return;
+    };
+
+    // 3. 写入并上传
+    let png = match parser::write_card_png(avatar, &parsed.data, &limits) {
+        Ok(p) => p,
+        Err(e) => {
+            event.reply(card_error_reply("打包", &e));
+            return;
+        }
+    };
+
+    let timestamp = kovi::chrono::Local::now().format("%H%M%S").to_string();
+    let png_filename = format!(
+        "{}_{}.png",
+        utils::safe_file_name(&parsed.data.name),
+        timestamp
+    );
+    let png_path = bot.get_data_path().join(&png_filename);
+    if let Err(e) = std::fs::write(&png_path, &png) {
+        event.reply(format!("❌ 写入临时文件失败: {}", e));
+        return;
+    }
+
+    if utils::upload_file(bot, event, &png_path, &png_filename).await {
+        event.reply(format!(
+            "✅ 打包完成: {}\n已写入 ccv3 与 chara 数据块 ({:.1} KB)",
+            parsed.data.name,
+            png.len() as f64 / 1024.0
+        ));
+    } else {
+        event.reply("⚠️ 文件上传失败，请检查日志。");
+    }
+
+    let _ = std::fs::remove_file(&png_path);
+}
+
+/// 正则试运行：按顺序执行角色卡中启用的正则脚本，逐步回复处理前后的文本
+///
+/// 参数为示例文本，开头可以写作用位置 (用户 / AI / 命令 / 世界书 / 推理)，
+/// 只运行 placement 包含该位置的脚本；按最新一条消息 (深度 0) 判断深度限制。
+async fn handle_regex_test(
+    event: &Arc<MsgEvent>,
+    bot: &Arc<RuntimeBot>,
+    cfg: &config::Config,
+    args: &str,
+) {
+    const PLACEMENTS: &[(&str, i32)] = &[
+        ("用户", 1),
+        ("AI", 2),
+        ("命令", 3),
+        ("世界书", 5),
+        ("推理", 6),
+    ];
+    /// 每一步只展示前若干字，避免回复过长
+    const PREVIEW_CHARS: usize = 300;
+
+    let (placement, sample) = match args.split_once(char::is_whitespace) {
+        Some((head, rest)) => match PLACEMENTS.iter().find(|(name, _)| head == *name) {
+            Some(&(_, p)) => (p, rest.trim()),
+            None => (2, args),
+        },
+        None => (2, args),
+    };
+    if sample.is_empty() {
+        event.reply(
+            "⚠️ 请在指令后写上示例文本，并附带或引用角色卡，如：测正则 AI <状态>心情: 好</状态>",
+        );
+        return;
+    }
+
+    let Some(parsed) = download_card(event, bot, cfg).await else {
+        return;
+    };
+    let card = &parsed.data;
+    let scripts = card
+        .extensions
+        .as_ref()
+        .map_or(&[][..], |ext| ext.regex_scripts.as_slice());
+    if scripts.is_empty() {
+        event.reply(format!("⚠️ {} 没有正则脚本", card.name));
+        return;
+    }
+
+    let preview = |text: &str| {
+        if text.chars().count() > PREVIEW_CHARS {
+            format!("{}…", text.chars().take(PREVIEW_CHARS).collect::<String>())
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut reply = format!(
+        "🧪 正则试运行: {} (作用位置: {})\n\n原文:\n{}\n",
+        card.name,
+        jsregex::placement_name(placement),
+        preview(sample)
+    );
+    let mut text = sample.to_string();
+    let mut skipped = 0;
+    for (i, script) in scripts.iter().enumerate() {
+        // 示例文本视为最新一条消息 (深度 0)
+        if !jsregex::runs_at(script, placement, 0) {
+            skipped += 1;
+            continue;
+        }
+
+        let mut mode = String::new();
+        if script.markdown_only {
+            mode.push_str(" [仅显示]");
+        }
+        if script.prompt_only {
+            mode.push_str(" [仅提示词]");
+        }
+        match jsregex::run_script(script, &text, &card.name) {
+            Ok((_, 0)) => {
+                reply.push_str(&format!(
+                    "\n#{} {}{}: 未匹配\n",
+                    i + 1,
+                    script.script_name,
+                    mode
+                ));
+            }
+            Ok((after, n)) => {
+                reply.push_str(&format!(
+                    "\n#{} {}{}: 匹配 {} 处\n{}\n",
+                    i + 1,
+                    script.script_name,
+                    mode,
+                    n,
+                    preview(&after)
+                ));
+                text = after;
+            }
+            Err(e) => {
+                reply.push_str(&format!(
+                    "\n#{} {}{}: ❌ {}\n",
+                    i + 1,
+                    script.script_name,
+                    mode,
+                    e
+                ));
+            }
+        }
+    }
+
+    if skipped > 0 {
+        reply.push_str(&format!(
+            "\n(已跳过 {} 个禁用、位置不符或受深度限制的脚本)",
+            skipped
+        ));
+    }
+    event.reply(reply);
+}
+
+/// 世界书模拟：用一段聊天记录测试角色卡 (或独立世界书) 中会激活哪些条目
+///
+/// 参数的每一行视为一条消息，最后一行为最新消息。
+async fn handle_lore_test(
+    event: &Arc<MsgEvent>,
+    bot: &Arc<RuntimeBot>,
+    cfg: &config::Config,
+    args: &str,
+) {
+    let messages: Vec<&str> = args
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    if messages.is_empty() {
+        event.reply("⚠️ 请在指令后写上聊天内容 (每行一条消息)，并附带或引用角色卡或世界书");
+        return;
+    }
+
+    let Some(parsed) = download_card(event, bot, cfg).await else {
+        return;
+    };
+    let Some(book) = parsed
+        .data
+        .character_book
+        .as_ref()
+        .filter(|b| !b.entries.is_empty())
+    else {
+        event.reply(format!("⚠️ {} 没有世界书条目", parsed.data.name));
+        return;
+    };
+
+    let activated = activation::simulate(book, &messages);
+    let total: usize = activated.iter().map(|a| a.tokens).sum();
+    let mut reply = format!(
+        "📚 世界书模拟: {} ({} 条消息，扫描深度 {})\n",
+        if book.name.is_empty() {
+            &parsed.data.name
+        } else {
+            &book.name
+        },
+        messages.len(),
+        book.scan_depth
+            .map_or("默认 2".to_string(), |d| d.to_string())
+    );
+    if activated.is_empty() {
+        reply.push_str("没有条目被激活");
+        event.reply(reply);
+        return;
+    }
+
+    reply.push_str(&format!(
+        "激活 {} / {} 条，约 {} tokens (按插入顺序):\n",
+        activated.len(),
+        book.entries.len(),
+        total
+    ));
+    for (i, a) in activated.iter().enumerate() {
+        let reason = match &a.reason {
+            activation::Reason::Constant => "常驻".to_string(),
+            activation::Reason::Decorator => "@@activate".to_string(),
+            activation::Reason::Key(key) => format!("关键词「{}」", key),
+            activation::Reason::Recursion { key, source } => {
+                let source = source.map_or("未知来源".to_string(), |i| {
+                    book.entries[i].display_name()
+                });
+                format!("递归: 关键词「{}」来自 {}", key, source)
+            }
+        };
+        reply.push_str(&format!(
+            "\n{}. [{}] {} — {} | 约 {} tokens",
+            i + 1,
+            a.entry.insertion_order,
+            a.entry.display_name(),
+            reason,
+            a.tokens
+        ));
+
+        // 依赖随机数或运行时状态的设置，只作提示
+        let ext = &a.entry.extensions;
+        if ext.use_probability != Some(false) && ext.probability.is_some_and(|p| p < 100) {
+            reply.push_str(&format!(" | 概率 {}%", ext.probability.unwrap_or(100)));
+        }
+        if let Some(group) = ext.group.as_deref().filter(|g| !g.is_empty()) {
+            reply.push_str(&format!(" | 分组 {}", group));
+        }
+    }
+    event.reply(reply);
+}
+
+/// 表格导入：把编辑后的世界书表格合并回角色卡，重新生成卡片
+///
+/// 附件中能解析的角色卡 / 世界书作为底稿，另一个文件作为表格 (CSV / XLSX)。
+/// 底稿是 PNG 时输出新的 PNG 卡片，独立世界书输出酒馆世界书文件，其余输出 V3 JSON。
+async fn handle_sheet_import(event: &Arc<MsgEvent>, bot: &Arc<RuntimeBot>, cfg: &config::Config) {
+    let urls = utils::get_attachment_urls(event, bot).await;
+    if urls.len() < 2 {
+        event.reply("⚠️ 请同时附带或引用角色卡 (PNG / JSON / CharX) 与世界书表格 (CSV / XLSX)");
+        return;
+    }
+
+    let mut files = Vec::new();
+    for url in urls.iter().take(2) {
+        match utils::download(url).await {
+            Ok(b) => files.push(b),
+            Err(e) => {
+                event.reply(e);
+                return;
+            }
+        }
+    }
+
+    let limits = cfg.png_limits();
+    let Some((card_index, mut parsed)) = files
+        .iter()
+        .enumerate()
+        .find_map(|(i, b)| parser::parse_card(b, &limits).ok().map(|p| (i, p)))
+    else {
+        event.reply("❌ 附件中没有可读取的角色卡或世界书");
+        return;
+    };
+    let card_bytes = &files[card_index];
+    let rows = match sheet::read(&files[1 - card_index]) {
+        Ok(rows) => rows,
+        Err(e) => {
+            event.reply(format!("❌ 表格读取失败: {}", e));
+            return;
+        }
+    };
+
+    let card = &mut parsed.data;
+    let book = card
+        .character_book
+        .get_or_insert_with(|| types::CharacterBook {
+            name: card.name.clone(),
+            ..Default::default()
+        });
+    let summary = match sheet::merge(book, &rows) {
+        Ok(s) => s,
+        Err(e) => {
+            event.reply(format!("❌ 表格合并失败: {}", e));
+            return;
+        }
+    };
+    let total = book.entries.len();
+
+    // 按底稿类型重新生成
+    let safe_name = utils::safe_file_name(&card.name);
+    let timestamp = kovi::chrono::Local::now().format("%H%M%S").to_string();
+    let output = if parsed.kind == parser::CardKind::Lorebook {
+        let world = worldinfo::from_character_book(book);
+        serde_json::to_vec_pretty(&world)
+            .map(|b| (b, format!("{}_{}_world.json", safe_name, timestamp)))
+            .map_err(anyhow::Error::from)
+    } else if parser::is_png(card_bytes) {
+        parser::write_card_png(card_bytes, card, &limits)
+            .map(|b| (b, format!("{}_{}.png", safe_name, timestamp)))
+            .map_err(anyhow::Error::from)
+    } else {
+        serde_json::to_vec_pretty(&parser::ccv3_json(card))
+            .map(|b| (b, format!("{}_{}.json", safe_name, timestamp)))
+            .map_err(anyhow::Error::from)
+    };
+    let (bytes, filename) = match output {
+        Ok(o) => o,
+        Err(e) => {
+            event.reply(format!("❌ 生成卡片失败: {}", e));
+            return;
+        }
+    };
+
+    let path = bot.get_data_path().join(&filename);
+    if let Err(e) = std::fs::write(&path, &bytes) {
+        event.reply(format!("❌ 写入临时文件失败: {}", e));
+        return;
+    }
+    if utils::upload_file(bot, event, &path, &filename).await {
+        event.reply(format!(
+            "✅ 表格已合并: {}\n更新 {} 条，新增 {} 条，世界书共 {} 条",
+            card.name, summary.updated, summary.added, total
+        ));
+    } else {
+        event.reply("⚠️ 文件上传失败，请检查日志。");
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+/// 下载附件并返回第一个可读取的角色卡或世界书，失败时直接回复原因
+async fn download_card(
+    event: &Arc<MsgEvent>,
+    bot: &Arc<RuntimeBot>,
+    cfg: &config::Config,
+) -> Option<parser::ParsedCard> {
+    let urls = utils::get_attachment_urls(event, bot).await;
+    if urls.is_empty() {
+        event.reply("⚠️ 请附带角色卡图片，或引用角色卡图片 / 文件消息");
+        return None;
+    }
+
+    let mut last_error = None;
+    for url in urls.iter().take(2) {
+        let bytes = match utils::download(url).await {
+            Ok(b) => b,
+            Err(e) => {
+                event.reply(e);
+                return None;
+            }
+        };
+        match parser::parse_card(&bytes, &cfg.png_limits()) {
+            Ok(p) => return Some(p),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    if let Some(e) = last_error {
+        event.reply(card_error_reply("解析", &e));
+    }
+    None
+}
+
+/// 按失败环节给出提示，告诉用户下一步该怎么做
+fn card_error_reply(action: &str, e: &CardError) -> String {
+    let hint = match e {
+        CardError::UnknownFormat => "请发送 PNG / WebP / JPEG 角色卡图片，或 .charx / .json 文件",
+        CardError::NotFound { .. } => {
+            "QQ 以图片形式发送时可能压缩并丢弃了角色卡数据，请以文件形式发送原图，或勾选“原图”"
+        }
+        CardError::MissingIend
+        | CardError::TruncatedHeader { .. }
+        | CardError::TruncatedChunk { .. } => "文件不完整，可能下载中断或被截断，请重新发送",
+        CardError::InvalidChunkType { .. }
+        | CardError::CrcMismatch { .. }
+        | CardError::Malformed { .. }
+        | CardError::NotPng => "文件已损坏，请从原始来源重新获取",
+        CardError::ChunkTooLarge { .. }
+        | CardError::TotalTooLarge { .. }
+        | CardError::EntryTooLarge { .. } => {
+            "如确需读取，可在配置中调大 png_max_chunk_mb / png_max_total_mb"
+        }
+        CardError::Base64 { .. } | CardError::Utf8 { .. } => {
+            "角色卡数据编码已损坏，可尝试用酒馆打开后重新导出"
+        }
+        CardError::Json { path: Some(_), .. } => {
+            "某个字段的类型与规范不符，请用编辑器修正该字段后重新导出"
+        }
+        CardError::Json { .. } => "角色卡 JSON 格式有误，请检查括号、引号与逗号",
+        CardError::EmptyCard { .. } | CardError::NotAnObject => "文件内容不像角色卡或世界书",
+        CardError::Archive(_) | CardError::MissingCardJson => "CharX 压缩包不完整，请重新导出",
+        CardError::Avatar(_) => "请换一张 PNG 头像",
+        CardError::Serialize(_) => "请将此问题反馈给插件作者",
+    };
+    format!("❌ {}失败: {}\n💡 {}", action, e, hint)
 }